@@ -0,0 +1,270 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::RwLock as TokioRwLock;
+use tracing::info;
+use uuid::Uuid;
+
+const MIN_USERNAME_LEN: usize = 3;
+const MAX_USERNAME_LEN: usize = 20;
+const MIN_PASSWORD_LEN: usize = 8;
+
+// A registered user account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: u64,
+}
+
+// A login session, referenced by the `jti` claim of its token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub account_id: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    InvalidUsername,
+    WeakPassword,
+    UsernameTaken,
+    InvalidCredentials,
+    Hash(String),
+    Storage(io::Error),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::InvalidUsername => write!(
+                f,
+                "username must be {}-{} letters, digits, '_' or '-'",
+                MIN_USERNAME_LEN, MAX_USERNAME_LEN
+            ),
+            AccountError::WeakPassword => write!(
+                f,
+                "password must be at least {} characters",
+                MIN_PASSWORD_LEN
+            ),
+            AccountError::UsernameTaken => write!(f, "username is already taken"),
+            AccountError::InvalidCredentials => write!(f, "invalid username or password"),
+            AccountError::Hash(e) => write!(f, "password hashing failed: {}", e),
+            AccountError::Storage(e) => write!(f, "account storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<io::Error> for AccountError {
+    fn from(e: io::Error) -> Self {
+        AccountError::Storage(e)
+    }
+}
+
+// Everything the account store persists
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AccountsData {
+    // account_id -> Account
+    accounts: HashMap<String, Account>,
+    // session_id -> Session
+    sessions: HashMap<String, Session>,
+}
+
+// Accounts and sessions, kept in memory and optionally saved to a JSON file
+#[derive(Debug, Default)]
+pub struct AccountStore {
+    path: Option<PathBuf>,
+    data: TokioRwLock<AccountsData>,
+}
+
+impl AccountStore {
+    // store that forgets everything on restart
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    // load accounts from a JSON file, creating it on the first save
+    pub async fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut data = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice::<AccountsData>(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => AccountsData::default(),
+            Err(e) => return Err(e),
+        };
+
+        // expired sessions are useless, drop them on load
+        let now = now_secs();
+        data.sessions.retain(|_, session| session.expires_at > now);
+        info!(
+            "Loaded {} accounts and {} sessions from {}",
+            data.accounts.len(),
+            data.sessions.len(),
+            path.display()
+        );
+
+        Ok(Self {
+            path: Some(path),
+            data: TokioRwLock::new(data),
+        })
+    }
+
+    // create a new account with a hashed password
+    pub async fn register(&self, username: &str, password: &str) -> Result<Account, AccountError> {
+        if !is_valid_username(username) {
+            return Err(AccountError::InvalidUsername);
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountError::WeakPassword);
+        }
+        if self.find_by_username(username).await.is_some() {
+            return Err(AccountError::UsernameTaken);
+        }
+
+        let password_hash = hash_password(password.to_string()).await?;
+
+        let mut data = self.data.write().await;
+        // check again, another registration may have won the race while hashing
+        if find_by_username(&data, username).is_some() {
+            return Err(AccountError::UsernameTaken);
+        }
+        let account = Account {
+            id: Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            password_hash,
+            created_at: now_secs(),
+        };
+        data.accounts.insert(account.id.clone(), account.clone());
+        self.save(&data).await?;
+        Ok(account)
+    }
+
+    // check a username/password pair
+    pub async fn verify_credentials(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Account, AccountError> {
+        let account = self
+            .find_by_username(username)
+            .await
+            .ok_or(AccountError::InvalidCredentials)?;
+        if verify_password(password.to_string(), account.password_hash.clone()).await {
+            Ok(account)
+        } else {
+            Err(AccountError::InvalidCredentials)
+        }
+    }
+
+    // get an account by id
+    pub async fn get(&self, account_id: &str) -> Option<Account> {
+        let data = self.data.read().await;
+        data.accounts.get(account_id).cloned()
+    }
+
+    // get an account by username, ignoring case
+    pub async fn find_by_username(&self, username: &str) -> Option<Account> {
+        let data = self.data.read().await;
+        find_by_username(&data, username).cloned()
+    }
+
+    // record a new login session
+    pub async fn create_session(&self, session: Session) -> io::Result<()> {
+        let mut data = self.data.write().await;
+        let now = now_secs();
+        data.sessions.retain(|_, s| s.expires_at > now);
+        data.sessions.insert(session.id.clone(), session);
+        self.save(&data).await
+    }
+
+    // get a session that exists and has not expired
+    pub async fn active_session(&self, session_id: &str) -> Option<Session> {
+        let data = self.data.read().await;
+        data.sessions
+            .get(session_id)
+            .filter(|s| s.expires_at > now_secs())
+            .cloned()
+    }
+
+    // end a session, returns false if it didn't exist
+    pub async fn end_session(&self, session_id: &str) -> io::Result<bool> {
+        let mut data = self.data.write().await;
+        if data.sessions.remove(session_id).is_none() {
+            return Ok(false);
+        }
+        self.save(&data).await?;
+        Ok(true)
+    }
+
+    // write the whole store to disk (if file backed)
+    async fn save(&self, data: &AccountsData) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(data)?).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+}
+
+fn find_by_username<'a>(data: &'a AccountsData, username: &str) -> Option<&'a Account> {
+    data.accounts
+        .values()
+        .find(|a| a.username.eq_ignore_ascii_case(username))
+}
+
+fn is_valid_username(username: &str) -> bool {
+    (MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// argon2 is deliberately slow, keep it off the async worker threads
+pub(crate) async fn hash_password(password: String) -> Result<String, AccountError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| AccountError::Hash(e.to_string()))
+    })
+    .await
+    .map_err(|e| AccountError::Hash(e.to_string()))?
+}
+
+pub(crate) async fn verify_password(password: String, password_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
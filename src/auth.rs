@@ -0,0 +1,144 @@
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// How long an issued token stays valid when no TTL is configured
+const DEFAULT_TOKEN_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+// Shortest accepted `AUTH_SECRET`, in bytes
+const MIN_SECRET_LEN: usize = 32;
+// Example secrets from docs and config templates, never accepted
+const PLACEHOLDER_SECRETS: &[&str] =
+    &["change-me-in-production", "change-me", "changeme", "secret"];
+
+// Auth payload sent by the client in the Socket.IO handshake: `io({ auth: { token } })`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthPayload {
+    pub token: String,
+}
+
+// JWT claims carried by a session token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    // account id
+    pub sub: String,
+    pub name: String,
+    // session id, revoked on logout
+    pub jti: String,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+// Verified identity of a connected socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub account_id: String,
+    pub username: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(jsonwebtoken::errors::Error),
+    SessionEnded,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "authentication token required"),
+            AuthError::InvalidToken(e) => write!(f, "invalid authentication token: {}", e),
+            AuthError::SessionEnded => write!(f, "session has ended, please log in again"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+// Signs and verifies HS256 session tokens
+#[derive(Clone)]
+pub struct TokenAuth {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl_secs: u64,
+}
+
+impl fmt::Debug for TokenAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenAuth")
+            .field("ttl_secs", &self.ttl_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenAuth {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            ttl_secs: DEFAULT_TOKEN_TTL_SECS,
+        }
+    }
+
+    // read the secret (`AUTH_SECRET`) and token lifetime (`AUTH_TOKEN_TTL_SECS`) from the environment
+    pub fn from_env() -> Result<Self, String> {
+        let secret = std::env::var("AUTH_SECRET").map_err(|_| "AUTH_SECRET must be set")?;
+        if secret.is_empty() {
+            return Err("AUTH_SECRET must not be empty".to_string());
+        }
+        if PLACEHOLDER_SECRETS.contains(&secret.to_lowercase().as_str()) {
+            return Err("AUTH_SECRET is a placeholder, set a random secret".to_string());
+        }
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "AUTH_SECRET must be at least {} bytes",
+                MIN_SECRET_LEN
+            ));
+        }
+        let ttl_secs = std::env::var("AUTH_TOKEN_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TOKEN_TTL_SECS);
+        Ok(Self {
+            ttl_secs,
+            ..Self::new(secret.as_bytes())
+        })
+    }
+
+    // issue a signed token for a new session of an account
+    pub fn issue(&self, account_id: &str, username: &str) -> Result<(String, Claims), AuthError> {
+        let iat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = Claims {
+            sub: account_id.to_string(),
+            name: username.to_string(),
+            jti: uuid::Uuid::new_v4().to_string(),
+            iat,
+            exp: iat + self.ttl_secs,
+        };
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .map_err(AuthError::InvalidToken)?;
+        Ok((token, claims))
+    }
+
+    // check the signature and expiry of a token
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        if token.is_empty() {
+            return Err(AuthError::MissingToken);
+        }
+        decode::<Claims>(token, &self.decoding, &Validation::new(Algorithm::HS256))
+            .map(|data| data.claims)
+            .map_err(AuthError::InvalidToken)
+    }
+}
+
+impl Default for TokenAuth {
+    // random secret, tokens don't survive a restart
+    fn default() -> Self {
+        Self::new(uuid::Uuid::new_v4().as_bytes())
+    }
+}
@@ -0,0 +1,219 @@
+use crate::accounts::AccountError;
+use crate::auth::AuthError;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::{fmt, io};
+
+// Everything that can make a client request fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatError {
+    // the payload could not be deserialized
+    InvalidPayload(String),
+    // the request carries no valid session
+    Unauthenticated,
+    // the socket is not a member of the given room
+    NotInRoom(String),
+    // another account uses this name
+    UsernameTaken,
+    // no account matches the addressee of a direct message
+    UnknownRecipient(String),
+    // no message with this id in the room
+    MessageNotFound(String),
+    // the user isn't allowed to do this, e.g. edit someone else's message
+    Forbidden(String),
+    EditWindowExpired,
+    RateLimited { retry_after_secs: u64 },
+    RoomFull(String),
+    // create_room with the name of an existing room
+    RoomExists(String),
+    // update_room on a room that doesn't exist
+    RoomNotFound(String),
+    // join_room on a password protected room without the right password
+    WrongRoomPassword(String),
+    // join_room on an invite-only room without an invitation
+    InviteRequired(String),
+    // no pending invitation with this id for the user
+    InvitationNotFound(String),
+    // the invite code doesn't exist, expired or was used up
+    InvalidInviteCode,
+    // join_room on a room the user is banned from
+    Banned { room: String, until: Option<u64> },
+    // send_message or typing in a room the user is muted in
+    Muted { room: String, until: Option<u64> },
+    // a message filter refused the message
+    MessageRejected { filter: String, reason: String },
+    // the payload was well formed but its content was refused
+    ValidationFailed(String),
+    InvalidCredentials,
+    Storage(String),
+    Internal(String),
+}
+
+impl ChatError {
+    // stable machine readable code sent to clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatError::InvalidPayload(_) => "invalid_payload",
+            ChatError::Unauthenticated => "unauthenticated",
+            ChatError::NotInRoom(_) => "not_in_room",
+            ChatError::UsernameTaken => "username_taken",
+            ChatError::UnknownRecipient(_) => "unknown_recipient",
+            ChatError::MessageNotFound(_) => "message_not_found",
+            ChatError::Forbidden(_) => "forbidden",
+            ChatError::EditWindowExpired => "edit_window_expired",
+            ChatError::RateLimited { .. } => "rate_limited",
+            ChatError::RoomFull(_) => "room_full",
+            ChatError::RoomExists(_) => "room_exists",
+            ChatError::RoomNotFound(_) => "room_not_found",
+            ChatError::WrongRoomPassword(_) => "wrong_room_password",
+            ChatError::InviteRequired(_) => "invite_required",
+            ChatError::InvitationNotFound(_) => "invitation_not_found",
+            ChatError::InvalidInviteCode => "invalid_invite_code",
+            ChatError::Banned { .. } => "banned",
+            ChatError::Muted { .. } => "muted",
+            ChatError::MessageRejected { .. } => "message_rejected",
+            ChatError::ValidationFailed(_) => "validation_failed",
+            ChatError::InvalidCredentials => "invalid_credentials",
+            ChatError::Storage(_) => "storage_error",
+            ChatError::Internal(_) => "internal_error",
+        }
+    }
+
+    // extra fields sent along with the message, if any
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            ChatError::NotInRoom(room)
+            | ChatError::RoomFull(room)
+            | ChatError::RoomExists(room)
+            | ChatError::RoomNotFound(room)
+            | ChatError::WrongRoomPassword(room)
+            | ChatError::InviteRequired(room) => Some(json!({ "room": room })),
+            ChatError::MessageNotFound(id) => Some(json!({ "message_id": id })),
+            ChatError::InvitationNotFound(id) => Some(json!({ "invitation_id": id })),
+            ChatError::Banned { room, until } | ChatError::Muted { room, until } => {
+                Some(json!({ "room": room, "until": until }))
+            }
+            ChatError::MessageRejected { filter, .. } => Some(json!({ "filter": filter })),
+            ChatError::UnknownRecipient(to) => Some(json!({ "to": to })),
+            ChatError::RateLimited { retry_after_secs } => {
+                Some(json!({ "retry_after_secs": retry_after_secs }))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ChatError::InvalidPayload(_)
+            | ChatError::ValidationFailed(_)
+            | ChatError::MessageRejected { .. } => StatusCode::BAD_REQUEST,
+            ChatError::Unauthenticated | ChatError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ChatError::NotInRoom(_)
+            | ChatError::Forbidden(_)
+            | ChatError::EditWindowExpired
+            | ChatError::WrongRoomPassword(_)
+            | ChatError::InviteRequired(_)
+            | ChatError::Banned { .. }
+            | ChatError::Muted { .. } => StatusCode::FORBIDDEN,
+            ChatError::MessageNotFound(_)
+            | ChatError::UnknownRecipient(_)
+            | ChatError::InvitationNotFound(_)
+            | ChatError::InvalidInviteCode => StatusCode::NOT_FOUND,
+            ChatError::UsernameTaken | ChatError::RoomFull(_) | ChatError::RoomExists(_) => {
+                StatusCode::CONFLICT
+            }
+            ChatError::RoomNotFound(_) => StatusCode::NOT_FOUND,
+            ChatError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ChatError::Storage(_) | ChatError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // `{ message, ...details }`, the error body shared by acks, `error` events and REST
+    pub fn to_json(&self) -> serde_json::Value {
+        // internal failures are logged server side, clients get a generic message
+        let message = match self {
+            ChatError::Storage(_) => "the request could not be stored, please retry".to_string(),
+            ChatError::Internal(_) => "something went wrong, please retry".to_string(),
+            _ => self.to_string(),
+        };
+        let mut body = json!({ "message": message });
+        if let Some(serde_json::Value::Object(details)) = self.details() {
+            body.as_object_mut().unwrap().extend(details);
+        }
+        body
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::InvalidPayload(e) => write!(f, "invalid payload: {}", e),
+            ChatError::Unauthenticated => write!(f, "authentication required"),
+            ChatError::NotInRoom(room) => write!(f, "you are not in room {}", room),
+            ChatError::UsernameTaken => write!(f, "username is already taken"),
+            ChatError::MessageNotFound(_) => write!(f, "message not found"),
+            ChatError::UnknownRecipient(to) => write!(f, "no user named {}", to),
+            ChatError::Forbidden(reason) => write!(f, "{}", reason),
+            ChatError::EditWindowExpired => write!(f, "message can no longer be edited"),
+            ChatError::RateLimited { retry_after_secs } => {
+                write!(f, "too many requests, retry in {}s", retry_after_secs)
+            }
+            ChatError::RoomFull(room) => write!(f, "room {} is full", room),
+            ChatError::RoomExists(room) => write!(f, "room {} already exists", room),
+            ChatError::RoomNotFound(room) => write!(f, "room {} does not exist", room),
+            ChatError::WrongRoomPassword(room) => {
+                write!(f, "wrong or missing password for room {}", room)
+            }
+            ChatError::InviteRequired(room) => write!(f, "room {} is invite only", room),
+            ChatError::InvitationNotFound(_) => write!(f, "invitation not found"),
+            ChatError::InvalidInviteCode => write!(f, "invite code is invalid or expired"),
+            ChatError::Banned { room, .. } => write!(f, "you are banned from room {}", room),
+            ChatError::Muted { room, .. } => write!(f, "you are muted in room {}", room),
+            ChatError::MessageRejected { reason, .. } => write!(f, "{}", reason),
+            ChatError::ValidationFailed(reason) => write!(f, "{}", reason),
+            ChatError::InvalidCredentials => write!(f, "invalid username or password"),
+            ChatError::Storage(e) => write!(f, "storage error: {}", e),
+            ChatError::Internal(e) => write!(f, "internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl From<io::Error> for ChatError {
+    fn from(e: io::Error) -> Self {
+        ChatError::Storage(e.to_string())
+    }
+}
+
+impl From<AuthError> for ChatError {
+    fn from(_: AuthError) -> Self {
+        ChatError::Unauthenticated
+    }
+}
+
+impl From<AccountError> for ChatError {
+    fn from(e: AccountError) -> Self {
+        match e {
+            AccountError::InvalidUsername | AccountError::WeakPassword => {
+                ChatError::ValidationFailed(e.to_string())
+            }
+            AccountError::UsernameTaken => ChatError::UsernameTaken,
+            AccountError::InvalidCredentials => ChatError::InvalidCredentials,
+            AccountError::Hash(e) => ChatError::Internal(e),
+            AccountError::Storage(e) => ChatError::Storage(e.to_string()),
+        }
+    }
+}
+
+// REST handlers answer with the status code and `{ error_code, message, ... }`
+impl IntoResponse for ChatError {
+    fn into_response(self) -> Response {
+        let mut body = self.to_json();
+        body["error_code"] = json!(self.code());
+        (self.status_code(), axum::Json(body)).into_response()
+    }
+}
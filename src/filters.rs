@@ -0,0 +1,329 @@
+use crate::models::RoomSettings;
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex as TokioMutex;
+
+// Default heuristics, used when the matching environment variable is not set
+const DEFAULT_DUPLICATE_WINDOW_SECS: u64 = 30;
+const DEFAULT_MAX_CAPS_PERCENT: usize = 70;
+const DEFAULT_MAX_REPEATED_CHARS: usize = 10;
+const DEFAULT_MAX_LINES: usize = 20;
+// Messages shorter than this (in letters) are never treated as shouting
+const MIN_CAPS_LETTERS: usize = 8;
+
+// What a filter decided about a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterOutcome {
+    Allow,
+    // continue with this text instead
+    Rewrite(String),
+    // refuse the message, the reason is sent back to the sender
+    Reject(String),
+}
+
+// The message being filtered and where it is going
+#[derive(Debug, Clone, Copy)]
+pub struct FilterContext<'a> {
+    pub room: &'a str,
+    pub settings: &'a RoomSettings,
+    pub account_id: &'a str,
+    pub username: &'a str,
+    pub now: u64,
+    // the text replaces one of the author's messages instead of being sent
+    pub edit: bool,
+}
+
+// A step of the filter chain run on every message sent to a room
+#[async_trait]
+pub trait MessageFilter: Send + Sync + std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    async fn check(&self, ctx: &FilterContext<'_>, message: &str) -> FilterOutcome;
+
+    // called with the final text once a message the chain allowed has been stored
+    async fn sent(&self, _ctx: &FilterContext<'_>, _message: &str) {}
+}
+
+// Filters run in order, each one sees the text rewritten by the previous ones
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    // chain that lets every message through
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    // build the standard chain from the environment: `FILTER_WORDS` (comma separated words to
+    // mask), `FILTER_MAX_CAPS_PERCENT`, `FILTER_MAX_REPEATED_CHARS`, `FILTER_MAX_LINES` and
+    // `FILTER_DUPLICATE_WINDOW_SECS`; a value of 0 turns the matching filter off
+    pub fn from_env() -> Self {
+        let words: Vec<String> = std::env::var("FILTER_WORDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|word| word.trim().to_string())
+            .filter(|word| !word.is_empty())
+            .collect();
+        let mut chain = Self::new();
+        if !words.is_empty() {
+            chain = chain.with(WordFilter::new(words));
+        }
+        chain = chain.with(LinkFilter);
+        let caps = env_setting("FILTER_MAX_CAPS_PERCENT", DEFAULT_MAX_CAPS_PERCENT);
+        if caps > 0 {
+            chain = chain.with(CapsFilter {
+                max_caps_percent: caps,
+            });
+        }
+        let repeated = env_setting("FILTER_MAX_REPEATED_CHARS", DEFAULT_MAX_REPEATED_CHARS);
+        let lines = env_setting("FILTER_MAX_LINES", DEFAULT_MAX_LINES);
+        if repeated > 0 || lines > 0 {
+            chain = chain.with(FloodFilter {
+                max_repeated_chars: repeated,
+                max_lines: lines,
+            });
+        }
+        let window = env_setting(
+            "FILTER_DUPLICATE_WINDOW_SECS",
+            DEFAULT_DUPLICATE_WINDOW_SECS,
+        );
+        if window > 0 {
+            chain = chain.with(DuplicateFilter::new(window));
+        }
+        chain
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    // run every filter, the final text or the first rejection with the name of its filter
+    pub async fn run(
+        &self,
+        ctx: &FilterContext<'_>,
+        message: String,
+    ) -> Result<String, (&'static str, String)> {
+        let mut message = message;
+        for filter in &self.filters {
+            match filter.check(ctx, &message).await {
+                FilterOutcome::Allow => {}
+                FilterOutcome::Rewrite(text) => message = text,
+                FilterOutcome::Reject(reason) => return Err((filter.name(), reason)),
+            }
+        }
+        Ok(message)
+    }
+
+    // tell every filter a message was stored
+    pub async fn sent(&self, ctx: &FilterContext<'_>, message: &str) {
+        for filter in &self.filters {
+            filter.sent(ctx, message).await;
+        }
+    }
+}
+
+fn env_setting<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// Masks listed words with `*`, ignoring case. Only whole words are masked
+#[derive(Debug)]
+pub struct WordFilter {
+    // lowercase
+    words: Vec<String>,
+}
+
+impl WordFilter {
+    pub fn new(words: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            words: words.into_iter().map(|w| w.to_lowercase()).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageFilter for WordFilter {
+    fn name(&self) -> &'static str {
+        "words"
+    }
+
+    async fn check(&self, _ctx: &FilterContext<'_>, message: &str) -> FilterOutcome {
+        let mut masked = String::with_capacity(message.len());
+        let mut changed = false;
+        let mut word = String::new();
+        // a trailing separator flushes the last word
+        for c in message.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                if self.words.contains(&word.to_lowercase()) {
+                    masked.extend(std::iter::repeat_n('*', word.chars().count()));
+                    changed = true;
+                } else {
+                    masked.push_str(&word);
+                }
+                word.clear();
+            }
+            masked.push(c);
+        }
+        masked.pop();
+        if changed {
+            FilterOutcome::Rewrite(masked)
+        } else {
+            FilterOutcome::Allow
+        }
+    }
+}
+
+// Refuses links in rooms with the `block_links` setting
+#[derive(Debug)]
+pub struct LinkFilter;
+
+#[async_trait]
+impl MessageFilter for LinkFilter {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    async fn check(&self, ctx: &FilterContext<'_>, message: &str) -> FilterOutcome {
+        if !ctx.settings.block_links {
+            return FilterOutcome::Allow;
+        }
+        let has_link = message.split_whitespace().any(|word| {
+            let word = word.to_lowercase();
+            word.starts_with("http://") || word.starts_with("https://") || word.starts_with("www.")
+        });
+        if has_link {
+            FilterOutcome::Reject(format!("links are not allowed in room {}", ctx.room))
+        } else {
+            FilterOutcome::Allow
+        }
+    }
+}
+
+// Lowercases messages written mostly in capitals
+#[derive(Debug)]
+pub struct CapsFilter {
+    pub max_caps_percent: usize,
+}
+
+#[async_trait]
+impl MessageFilter for CapsFilter {
+    fn name(&self) -> &'static str {
+        "caps"
+    }
+
+    async fn check(&self, _ctx: &FilterContext<'_>, message: &str) -> FilterOutcome {
+        let letters = message.chars().filter(|c| c.is_alphabetic()).count();
+        let caps = message.chars().filter(|c| c.is_uppercase()).count();
+        if letters >= MIN_CAPS_LETTERS && caps * 100 > letters * self.max_caps_percent {
+            FilterOutcome::Rewrite(message.to_lowercase())
+        } else {
+            FilterOutcome::Allow
+        }
+    }
+}
+
+// Shortens long runs of the same character and refuses messages with too many lines
+#[derive(Debug)]
+pub struct FloodFilter {
+    // 0 for no limit
+    pub max_repeated_chars: usize,
+    pub max_lines: usize,
+}
+
+#[async_trait]
+impl MessageFilter for FloodFilter {
+    fn name(&self) -> &'static str {
+        "flood"
+    }
+
+    async fn check(&self, _ctx: &FilterContext<'_>, message: &str) -> FilterOutcome {
+        if self.max_lines > 0 && message.lines().count() > self.max_lines {
+            return FilterOutcome::Reject(format!(
+                "messages can have at most {} lines",
+                self.max_lines
+            ));
+        }
+        if self.max_repeated_chars == 0 {
+            return FilterOutcome::Allow;
+        }
+        let mut shortened = String::with_capacity(message.len());
+        let mut previous = None;
+        let mut run = 0;
+        for c in message.chars() {
+            run = if previous == Some(c) { run + 1 } else { 1 };
+            previous = Some(c);
+            if run <= self.max_repeated_chars {
+                shortened.push(c);
+            }
+        }
+        if shortened.len() == message.len() {
+            FilterOutcome::Allow
+        } else {
+            FilterOutcome::Rewrite(shortened)
+        }
+    }
+}
+
+// Refuses a message identical to the last one its author stored in the room a moment ago.
+// Edits are let through
+#[derive(Debug)]
+pub struct DuplicateFilter {
+    window_secs: u64,
+    // (room, account_id) -> (lowercase text, sent at)
+    last: TokioMutex<HashMap<(String, String), (String, u64)>>,
+}
+
+impl DuplicateFilter {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            last: TokioMutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageFilter for DuplicateFilter {
+    fn name(&self) -> &'static str {
+        "duplicate"
+    }
+
+    async fn check(&self, ctx: &FilterContext<'_>, message: &str) -> FilterOutcome {
+        if ctx.edit {
+            return FilterOutcome::Allow;
+        }
+        let text = message.to_lowercase();
+        let mut last = self.last.lock().await;
+        // forget messages that can no longer be repeated
+        last.retain(|_, (_, at)| ctx.now.saturating_sub(*at) < self.window_secs);
+        let key = (ctx.room.to_string(), ctx.account_id.to_string());
+        if last
+            .get(&key)
+            .is_some_and(|(previous, _)| *previous == text)
+        {
+            return FilterOutcome::Reject("you already sent this message".to_string());
+        }
+        FilterOutcome::Allow
+    }
+
+    // only stored messages count, a send that failed can be retried
+    async fn sent(&self, ctx: &FilterContext<'_>, message: &str) {
+        let key = (ctx.room.to_string(), ctx.account_id.to_string());
+        let mut last = self.last.lock().await;
+        last.insert(key, (message.to_lowercase(), ctx.now));
+    }
+}
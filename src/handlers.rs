@@ -1,73 +1,241 @@
+use crate::accounts::Session;
+use crate::auth::{AuthError, AuthPayload, Identity};
+use crate::errors::ChatError;
+use crate::metrics::MetricsSnapshot;
 use crate::models::*;
-use crate::state::AppState;
-use socketioxide::extract::{Data, SocketRef, State};
+use crate::ratelimit::Penalty;
+use crate::state::{dm_room, user_room, AppState};
+use crate::validation::{normalize_room_name, Validate};
+use serde::{de::DeserializeOwned, Serialize};
+use socketioxide::extract::{AckSender, SocketRef, State, TryData};
 use std::{
-    collections::HashMap,
-    time::{SystemTime, UNIX_EPOCH},
+    future::Future,
+    net::{IpAddr, SocketAddr},
 };
 use tracing::{error, info, warn};
 
-// Handle client connection
-pub async fn on_connect(socket: SocketRef, app_state: State<AppState>) {
-    info!("Client connectd: {}", socket.id);
-
-    // send available rooms to the connected client
-    let rooms_info = app_state.get_rooms_info().await;
-    socket.emit("rooms_list", &rooms_info).ok();
-
-    // Handle join room event
-    socket.on("join_room", {
-        let app_state = app_state.clone();
-        move |socket: SocketRef, Data(data): Data<JoinRoomData>| {
-            let app_state = app_state.clone();
-            async move {
-                handle_join_room(socket, data, socketioxide::extract::State(app_state)).await;
-            }
+// Verify the handshake token before the socket joins the namespace
+pub async fn authenticate(
+    socket: SocketRef,
+    auth: Option<AuthPayload>,
+    app_state: State<AppState>,
+) -> Result<(), AuthError> {
+    let token = auth.map(|auth| auth.token).unwrap_or_default();
+    match app_state.authenticate_token(&token).await {
+        Ok(identity) => {
+            info!(
+                "Socket {} authenticated as {} ({})",
+                socket.id, identity.username, identity.account_id
+            );
+            app_state
+                .set_socket_identity(&socket.id.to_string(), identity)
+                .await;
+            Ok(())
         }
-    });
-
-    // Handle send message event
-    socket.on("send_message", {
-        let app_state = app_state.clone();
-        move |socket: SocketRef, Data(data): Data<SendMessageData>| {
-            let app_state = app_state.clone();
-            async move {
-                handle_send_message(socket, data, socketioxide::extract::State(app_state)).await;
-            }
+        Err(e) => {
+            warn!("Rejected socket {}: {}", socket.id, e);
+            Err(e)
         }
-    });
+    }
+}
 
-    // Handle typing events
-    socket.on("typing", {
-        let app_state = app_state.clone();
-        move |socket: SocketRef| {
-            let app_state = app_state.clone();
-            async move {
-                handle_typing(socket, socketioxide::extract::State(app_state), true).await;
-            }
+impl<T: Serialize> From<Result<T, ChatError>> for AckResponse {
+    fn from(result: Result<T, ChatError>) -> Self {
+        match result {
+            Ok(data) => AckResponse {
+                ok: true,
+                error_code: None,
+                data: serde_json::to_value(data).ok(),
+            },
+            Err(e) => AckResponse {
+                ok: false,
+                error_code: Some(e.code().to_string()),
+                data: Some(e.to_json()),
+            },
         }
-    });
+    }
+}
 
-    socket.on("stop_typing", {
-        let app_state = app_state.clone();
-        move |socket: SocketRef| {
+// Register a client event: the payload is deserialized, the handler is run and its result
+// is sent back through the Socket.IO ack (ignored when the client didn't ask for one).
+// Failures are also pushed as an `error` event and counted in the metrics.
+// Events over their rate limits are refused before the payload is even parsed,
+// payloads that fail validation never reach the handler
+fn on_event<T, R, F, Fut>(socket: &SocketRef, app_state: &AppState, event: &'static str, handler: F)
+where
+    T: DeserializeOwned + Validate + Send + Sync + 'static,
+    R: Serialize + Send,
+    F: Fn(SocketRef, T, State<AppState>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<R, ChatError>> + Send + 'static,
+{
+    let app_state = app_state.clone();
+    socket.on(
+        event,
+        move |socket: SocketRef, TryData(data): TryData<T>, ack: AckSender| {
             let app_state = app_state.clone();
+            let handler = handler.clone();
             async move {
-                handle_typing(socket, socketioxide::extract::State(app_state), false).await;
+                let ip = client_ip(&socket);
+                let socket_id = socket.id.to_string();
+                if let Err(limited) = app_state.check_rate_limit(event, &socket_id, ip).await {
+                    let e = ChatError::RateLimited {
+                        retry_after_secs: limited.retry_after_secs,
+                    };
+                    send_ack(&socket, &app_state, ack, event, Err::<(), _>(e)).await;
+                    if limited.penalty == Penalty::Disconnect {
+                        socket.disconnect().ok();
+                    }
+                    return;
+                }
+                let result = match data {
+                    Ok(mut data) => match data.validate() {
+                        Ok(()) => handler(socket.clone(), data, State(app_state.clone())).await,
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(ChatError::InvalidPayload(e.to_string())),
+                };
+                send_ack(&socket, &app_state, ack, event, result).await;
             }
-        }
-    });
+        },
+    );
+}
 
-    // In on_connect, add this after other socket.on handlers:
-    socket.on("leave_room", {
-        let app_state = app_state.clone();
-        move |socket: SocketRef, Data(data): Data<JoinRoomData>| {
-            let app_state = app_state.clone();
-            async move {
-                handle_leave_room(socket, data, socketioxide::extract::State(app_state)).await;
-            }
+// the address the socket connected from
+fn client_ip(socket: &SocketRef) -> Option<IpAddr> {
+    socket
+        .req_parts()
+        .extensions
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip())
+}
+
+// answer the client's ack, failures are logged, counted and pushed as an `error` event
+async fn send_ack<R: Serialize + Send>(
+    socket: &SocketRef,
+    app_state: &AppState,
+    ack: AckSender,
+    event: &str,
+    result: Result<R, ChatError>,
+) {
+    if let Err(e) = &result {
+        warn!("{} from {} failed: {} ({})", event, socket.id, e, e.code());
+        app_state.metrics.record_error(event, e).await;
+        let error_data = ErrorData {
+            event: event.to_string(),
+            error_code: e.code().to_string(),
+            data: e.to_json(),
+        };
+        socket.emit("error", &error_data).ok();
+    }
+    ack.send(&AckResponse::from(result)).ok();
+}
+
+// Handle client connection
+pub async fn on_connect(socket: SocketRef, app_state: State<AppState>) {
+    info!("Client connectd: {}", socket.id);
+
+    // every socket of an account listens on its personal room for direct messages
+    if let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await {
+        socket.join(user_room(&identity.account_id)).ok();
+
+        // invitations sent while the user was away
+        for invitation in app_state.pending_invitations(&identity.account_id).await {
+            socket.emit("invitation_received", &invitation).ok();
         }
+    }
+
+    // send available rooms to the connected client
+    emit_rooms_list(&socket, &app_state).await;
+
+    // Client events, each handler result is sent back through the ack if the client asked for one
+    on_event(&socket, &app_state, "join_room", handle_join_room);
+    on_event(&socket, &app_state, "create_room", handle_create_room);
+    on_event(&socket, &app_state, "update_room", handle_update_room);
+    on_event(&socket, &app_state, "grant_role", handle_grant_role);
+    on_event(
+        &socket,
+        &app_state,
+        "revoke_role",
+        |socket, data: RevokeRoleData, app_state| {
+            let grant = GrantRoleData {
+                room: data.room,
+                to: data.to,
+                role: RoomRole::Member,
+            };
+            handle_grant_role(socket, grant, app_state)
+        },
+    );
+    // moderation, each event is one action on the same payload
+    for (event, action) in [
+        ("kick_user", ModerationAction::Kick),
+        ("ban_user", ModerationAction::Ban),
+        ("unban_user", ModerationAction::Unban),
+        ("mute_user", ModerationAction::Mute),
+        ("unmute_user", ModerationAction::Unmute),
+    ] {
+        on_event(
+            &socket,
+            &app_state,
+            event,
+            move |socket, data, app_state| handle_moderation(socket, data, app_state, action),
+        );
+    }
+    on_event(&socket, &app_state, "invite_user", handle_invite_user);
+    on_event(&socket, &app_state, "accept_invite", handle_accept_invite);
+    on_event(&socket, &app_state, "decline_invite", handle_decline_invite);
+    on_event(
+        &socket,
+        &app_state,
+        "create_invite_code",
+        handle_create_invite_code,
+    );
+    on_event(&socket, &app_state, "send_message", handle_send_message);
+    on_event(&socket, &app_state, "edit_message", handle_edit_message);
+    on_event(&socket, &app_state, "delete_message", handle_delete_message);
+    on_event(
+        &socket,
+        &app_state,
+        "add_reaction",
+        |socket, data, app_state| handle_reaction(socket, data, app_state, true),
+    );
+    on_event(
+        &socket,
+        &app_state,
+        "remove_reaction",
+        |socket, data, app_state| handle_reaction(socket, data, app_state, false),
+    );
+    on_event(&socket, &app_state, "load_history", handle_load_history);
+    on_event(&socket, &app_state, "load_thread", handle_load_thread);
+    on_event(
+        &socket,
+        &app_state,
+        "send_direct_message",
+        handle_send_direct_message,
+    );
+    on_event(
+        &socket,
+        &app_state,
+        "load_direct_messages",
+        handle_load_direct_messages,
+    );
+    on_event(&socket, &app_state, "leave_room", handle_leave_room);
+    on_event(
+        &socket,
+        &app_state,
+        "mark_mentions_read",
+        handle_mark_mentions_read,
+    );
+
+    // typing events name the room the user is typing in
+    on_event(&socket, &app_state, "typing", |socket, data, app_state| {
+        handle_typing(socket, data, app_state, true)
     });
+    on_event(
+        &socket,
+        &app_state,
+        "stop_typing",
+        |socket, data, app_state| handle_typing(socket, data, app_state, false),
+    );
 
     // Handle disconnect
     socket.on_disconnect({
@@ -82,41 +250,97 @@ pub async fn on_connect(socket: SocketRef, app_state: State<AppState>) {
 }
 
 // Handle user joining a room
-async fn handle_join_room(socket: SocketRef, data: JoinRoomData, app_state: State<AppState>) {
+async fn handle_join_room(
+    socket: SocketRef,
+    data: JoinRoomData,
+    app_state: State<AppState>,
+) -> Result<JoinRoomData, ChatError> {
     let socket_id = socket.id.to_string();
     info!("User {} joining room: {}", socket_id, data.room);
 
+    // the username comes from the verified token, not from the client payload
+    let Some(identity) = app_state.get_socket_identity(&socket_id).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let password = data.password;
+    let data = JoinRoomData {
+        room: data.room,
+        username: identity.username.clone(),
+        password: None,
+    };
+
+    // joining a room twice only resends its history, the others aren't told again
+    if app_state
+        .get_user_in_room(&socket_id, &data.room)
+        .await
+        .is_some()
+    {
+        let history = app_state.get_history_page(&data.room, None, None).await;
+        socket.emit("room_messages", &history).ok();
+        socket.emit("joined_room", &data).ok();
+        return Ok(data);
+    }
+
     // create a new user
-    let user = User::new(data.username.clone(), data.room.clone(), socket_id.clone());
+    let user = User::new(
+        identity.account_id.clone(),
+        data.username.clone(),
+        data.room.clone(),
+        socket_id.clone(),
+    );
 
-    //  add user to  room
-    app_state.add_user_to_room(user.clone()).await;
+    //  check bans and the access mode of the room, then add user to  room
+    let result = match app_state
+        .check_not_banned(&data.room, &identity.account_id)
+        .await
+    {
+        Ok(()) => {
+            app_state
+                .check_join_access(&identity, &data.room, password.as_deref())
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let result = match result {
+        Ok(()) => app_state.add_user_to_room(user).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warn!(
+            "User {} can't join room {}: {}",
+            data.username, data.room, e
+        );
+        let join_error = JoinErrorData {
+            room: data.room.clone(),
+            code: e.code().to_string(),
+            reason: e.to_string(),
+        };
+        socket.emit("join_error", &join_error).ok();
+        return Err(e);
+    }
 
     // join the socket.io room
     socket.join(data.room.clone()).ok();
 
-    // send room history to the user
-    let messages = app_state.get_room_messages(&data.room).await;
-    socket.emit("room_messages", &messages).ok();
+    // send the latest page of room history to the user
+    let history = app_state.get_history_page(&data.room, None, None).await;
+    socket.emit("room_messages", &history).ok();
 
     // get updated room users
-    let room_users = app_state.get_room_users(&data.room).await;
-    let user_count = room_users.len();
-    let usernames: Vec<String> = room_users.iter().map(|u| u.username.clone()).collect();
+    let usernames = app_state.get_room_usernames(&data.room).await;
+    let user_count = usernames.len();
 
     // notify user they joined successfully
-    socket
-        .emit(
-            "joined_room",
-            &JoinRoomData {
-                room: data.room.clone(),
-                username: data.username.clone(),
-            },
-        )
-        .ok();
+    let joined = JoinRoomData {
+        room: data.room.clone(),
+        username: data.username.clone(),
+        password: None,
+    };
+    socket.emit("joined_room", &joined).ok();
 
     // send the updated uer list to all users in th room
     let room_users_data = RoomUsersData {
+        room: data.room.clone(),
         users: usernames,
         count: user_count,
     };
@@ -141,26 +365,230 @@ async fn handle_join_room(socket: SocketRef, data: JoinRoomData, app_state: Stat
         .ok();
 
     // create and broadcast system message
-    let system_message = ChatMessage {
-        id: uuid::Uuid::new_v4().to_string(),
-        username: "System".to_string(),
-        message: format!("{} has joined the room.", data.username),
-        room: data.room.clone(),
-        timestamp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-    };
+    let system_message = ChatMessage::system(
+        format!("{} has joined the room.", data.username),
+        data.room.clone(),
+    );
 
-    app_state.add_message(system_message.clone()).await;
+    if let Err(e) = app_state.add_message(system_message.clone()).await {
+        error!("failed to store system message: {}", e);
+    }
     socket
         .to(data.room.clone())
         .emit("new_message", &system_message)
         .ok();
 
     // update the room list for all the client.
-    let rooms_info = app_state.get_rooms_info().await;
-    socket.broadcast().emit("rooms_list", &rooms_info).ok();
+    broadcast_rooms_list(&socket, &app_state).await;
+
+    Ok(joined)
+}
+
+// handle creating a room, the creator still has to join it
+async fn handle_create_room(
+    socket: SocketRef,
+    data: CreateRoomData,
+    app_state: State<AppState>,
+) -> Result<Room, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    if data.persistent && !app_state.is_server_moderator(&identity.username) {
+        return Err(ChatError::Forbidden(
+            "only a server moderator can create a persistent room".to_string(),
+        ));
+    }
+    let mut room = Room::new(data.name).with_creator(identity.account_id, identity.username);
+    room.topic = data.topic;
+    room.description = data.description;
+    room.persistent = data.persistent;
+    room.settings = data.settings;
+    let room = app_state.create_room(room, data.password).await?;
+    info!("Room {} created by {:?}", room.name, room.creator);
+
+    // update the room list for every client, the creator included
+    broadcast_rooms_list(&socket, &app_state).await;
+    Ok(room)
+}
+
+// handle changing the topic, description or settings of a room
+async fn handle_update_room(
+    socket: SocketRef,
+    data: UpdateRoomData,
+    app_state: State<AppState>,
+) -> Result<Room, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let room = app_state.update_room(&identity, data).await?;
+    info!("Room {} updated by {}", room.name, identity.username);
+
+    // tell the members about the change, and everyone else through the room list
+    socket
+        .to(room.name.clone())
+        .emit("room_updated", &room)
+        .ok();
+    socket.emit("room_updated", &room).ok();
+    broadcast_rooms_list(&socket, &app_state).await;
+    Ok(room)
+}
+
+// handle changing the role of a user in a room, the room and the user are told
+async fn handle_grant_role(
+    socket: SocketRef,
+    data: GrantRoleData,
+    app_state: State<AppState>,
+) -> Result<RoleChangedData, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let changed = app_state
+        .set_room_role(&identity, &data.room, &data.to, data.role)
+        .await?;
+    info!(
+        "User {} made {} {:?} of room {}",
+        changed.by, changed.username, changed.role, changed.room
+    );
+    socket
+        .within(changed.room.clone())
+        .within(user_room(&changed.account_id))
+        .emit("role_changed", &changed)
+        .ok();
+    Ok(changed)
+}
+
+// handle a moderation action, kicked and banned users are taken out of the room
+async fn handle_moderation(
+    socket: SocketRef,
+    data: ModerationData,
+    app_state: State<AppState>,
+    action: ModerationAction,
+) -> Result<ModerationActionData, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let done = app_state.moderate(&identity, data, action).await?;
+    info!(
+        "User {} applied {:?} to {} in room {}",
+        done.by, done.action, done.username, done.room
+    );
+
+    // tell the room and every socket of the user, before they are removed
+    socket
+        .within(done.room.clone())
+        .within(user_room(&done.account_id))
+        .emit("moderation_action", &done)
+        .ok();
+    if matches!(action, ModerationAction::Kick | ModerationAction::Ban) {
+        kick_from_room(&socket, &app_state, &done.room, &done.account_id).await;
+    }
+    Ok(done)
+}
+
+// force every socket of an account out of a room, same as them leaving it
+async fn kick_from_room(socket: &SocketRef, app_state: &AppState, room: &str, account_id: &str) {
+    let sockets = socket
+        .within(room.to_string())
+        .sockets()
+        .unwrap_or_default();
+    let users = app_state.get_room_users(room).await;
+    for user in users.iter().filter(|u| u.account_id == account_id) {
+        let Some(user) = app_state.remove_user_from_room(&user.socket_id, room).await else {
+            continue;
+        };
+        // announced from the kicked socket so the moderator is told as well
+        let Some(target) = sockets.iter().find(|s| s.id.to_string() == user.socket_id) else {
+            continue;
+        };
+        target.leave(room.to_string()).ok();
+        announce_user_left(target, app_state, &user).await;
+    }
+
+    // Update rooms list for all clients
+    broadcast_rooms_list(socket, app_state).await;
+}
+
+// handle inviting a user to a room, every socket of the invited user is told
+async fn handle_invite_user(
+    socket: SocketRef,
+    data: InviteUserData,
+    app_state: State<AppState>,
+) -> Result<Invitation, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let invitation = app_state
+        .invite_user(&identity, &data.room, &data.to)
+        .await?;
+    info!(
+        "User {} invited {} to room {}",
+        invitation.from, invitation.to, invitation.room
+    );
+    socket
+        .within(user_room(&invitation.to_id))
+        .emit("invitation_received", &invitation)
+        .ok();
+    Ok(invitation)
+}
+
+// handle accepting an invitation, the socket joins the room right away
+async fn handle_accept_invite(
+    socket: SocketRef,
+    data: InvitationResponseData,
+    app_state: State<AppState>,
+) -> Result<JoinRoomData, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let invitation = app_state
+        .accept_invitation(&identity, &data.invitation_id)
+        .await?;
+    info!(
+        "User {} accepted the invitation to room {}",
+        identity.username, invitation.room
+    );
+    let join = JoinRoomData {
+        room: invitation.room,
+        username: identity.username,
+        password: None,
+    };
+    handle_join_room(socket, join, app_state).await
+}
+
+// handle declining an invitation, the inviter is told
+async fn handle_decline_invite(
+    socket: SocketRef,
+    data: InvitationResponseData,
+    app_state: State<AppState>,
+) -> Result<Invitation, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let invitation = app_state
+        .decline_invitation(&identity, &data.invitation_id)
+        .await?;
+    socket
+        .within(user_room(&invitation.from_id))
+        .emit("invitation_declined", &invitation)
+        .ok();
+    Ok(invitation)
+}
+
+// handle creating a shareable invite code for a room
+async fn handle_create_invite_code(
+    socket: SocketRef,
+    data: CreateInviteCodeData,
+    app_state: State<AppState>,
+) -> Result<InviteCode, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    let code = app_state.create_invite_code(&identity, data).await?;
+    info!(
+        "User {} created an invite code for room {}",
+        identity.username, code.room
+    );
+    Ok(code)
 }
 
 // handle sending a message
@@ -168,114 +596,319 @@ pub async fn handle_send_message(
     socket: SocketRef,
     data: SendMessageData,
     app_state: State<AppState>,
-) {
+) -> Result<ChatMessage, ChatError> {
     let socket_id = socket.id.to_string();
 
-    // get user info
-    if let Some(user) = app_state.get_user_by_socket_id(&socket_id).await {
+    // get user info, the socket must have joined the room it sends to
+    if let Some(user) = app_state.get_user_in_room(&socket_id, &data.room).await {
+        app_state
+            .check_not_muted(&data.room, &user.account_id)
+            .await?;
+        // the parent is looked up first, filters only see messages that can be sent
+        let parent = match &data.reply_to {
+            Some(reply_to) => Some(app_state.get_live_message(&data.room, reply_to).await?),
+            None => None,
+        };
+        let text = app_state.filter_message(&user, data.message).await?;
         info!(
             "User {} sending message to room {}: {}",
-            user.username, user.room, data.message
+            user.username, user.room, text
         );
 
-        // Validate that user is in the room they're trying to send to
-        if user.room != data.room {
-            warn!(
-                "User {} tried to send message to room {} but is in room {}",
-                user.username, data.room, user.room
-            );
-            return;
-        }
-
         // create message
-        let message = ChatMessage::new(user.username, data.message, data.room.clone());
+        let markup = app_state.render_markup(&text).await;
+        let mut message = ChatMessage::new(user.username.clone(), text, data.room.clone())
+            .with_author(user.account_id.clone())
+            .with_markup(markup);
+        if let Some(parent) = &parent {
+            message = message.in_reply_to(parent);
+        }
         // store message
-        app_state.add_message(message.clone()).await;
+        if let Err(e) = app_state.add_message(message.clone()).await {
+            error!("failed to store message from {}: {}", message.username, e);
+            return Err(e.into());
+        }
+        app_state.message_stored(&user, &message.message).await;
 
         // broadcast message to all users in the room (including sender)
-        socket.within(data.room).emit("new_message", &message).ok();
+        socket
+            .within(data.room.clone())
+            .emit("new_message", &message)
+            .ok();
+
+        // every socket of a mentioned user is told, whichever room it is looking at
+        for (account_id, mention) in app_state.notify_mentions(&message).await {
+            socket
+                .within(user_room(&account_id))
+                .emit("mention", &mention)
+                .ok();
+        }
+
+        // surface the new reply count on the thread root
+        if let Some(thread_id) = &message.thread_id {
+            match app_state.bump_reply_count(&data.room, thread_id).await {
+                Ok(root) => {
+                    let thread_updated = ThreadUpdatedData {
+                        room: data.room.clone(),
+                        thread_id: root.id,
+                        reply_count: root.reply_count,
+                        last_reply: message.clone(),
+                    };
+                    socket
+                        .within(data.room)
+                        .emit("thread_updated", &thread_updated)
+                        .ok();
+                }
+                Err(e) => warn!("failed to update thread {}: {}", thread_id, e),
+            }
+        }
+        Ok(message)
     } else {
-        error!("received message from unknown user : {}", socket_id);
+        warn!(
+            "Socket {} tried to send message to room {} without joining it",
+            socket_id, data.room
+        );
+        Err(ChatError::NotInRoom(data.room))
     }
 }
 
-// handle  typing indicator
+// handle the author editing one of their messages
+async fn handle_edit_message(
+    socket: SocketRef,
+    data: EditMessageData,
+    app_state: State<AppState>,
+) -> Result<ChatMessage, ChatError> {
+    let socket_id = socket.id.to_string();
+    let user = app_state.require_member(&socket_id, &data.room).await?;
+    let text = app_state.filter_edit(&user, data.message).await?;
+
+    let message = app_state
+        .edit_message(&user.account_id, &data.room, &data.message_id, text)
+        .await?;
+    info!("User {} edited message {}", user.username, message.id);
 
-async fn handle_typing(socket: SocketRef, app_state: State<AppState>, is_typing: bool) {
+    // everyone in the room (including the author) gets the new text
+    socket
+        .within(data.room)
+        .emit("message_edited", &message)
+        .ok();
+    Ok(message)
+}
+
+// handle the author or a moderator deleting a message
+async fn handle_delete_message(
+    socket: SocketRef,
+    data: DeleteMessageData,
+    app_state: State<AppState>,
+) -> Result<ChatMessage, ChatError> {
     let socket_id = socket.id.to_string();
+    let user = app_state.require_member(&socket_id, &data.room).await?;
 
-    if let Some(user) = app_state.get_user_by_socket_id(&socket_id).await {
-        let typing_data = TypingData {
-            username: user.username.clone(),
-            room: user.room.clone(),
-            is_typing,
-        };
+    let message = app_state
+        .delete_message(&user, &data.room, &data.message_id)
+        .await?;
+    info!("User {} deleted message {}", user.username, message.id);
 
-        // broadcast typing status to others in the room  (excluding sender)
-        socket.to(user.room).emit("user_typing", &typing_data).ok();
+    // the tombstone replaces the message for everyone in the room
+    socket
+        .within(data.room)
+        .emit("message_deleted", &message)
+        .ok();
+    Ok(message)
+}
+
+// handle adding or removing an emoji reaction
+async fn handle_reaction(
+    socket: SocketRef,
+    data: ReactionData,
+    app_state: State<AppState>,
+    reacted: bool,
+) -> Result<ReactionsUpdatedData, ChatError> {
+    let socket_id = socket.id.to_string();
+    let user = app_state.require_member(&socket_id, &data.room).await?;
+
+    let message = app_state
+        .set_reaction(
+            &user.username,
+            &data.room,
+            &data.message_id,
+            &data.emoji,
+            reacted,
+        )
+        .await?;
+
+    let reactions_updated = ReactionsUpdatedData {
+        room: data.room.clone(),
+        message_id: message.id,
+        reactions: message.reactions,
+    };
+    socket
+        .within(data.room)
+        .emit("reactions_updated", &reactions_updated)
+        .ok();
+    Ok(reactions_updated)
+}
+
+// handle loading an older page of room history
+async fn handle_load_history(
+    socket: SocketRef,
+    data: LoadHistoryData,
+    app_state: State<AppState>,
+) -> Result<HistoryPage, ChatError> {
+    let viewer = app_state.get_socket_identity(&socket.id.to_string()).await;
+    if !app_state.can_read_room(viewer.as_ref(), &data.room).await {
+        return Err(ChatError::NotInRoom(data.room));
+    }
+    let history = app_state
+        .get_history_page(&data.room, data.before.as_deref(), data.limit)
+        .await;
+    socket.emit("history_page", &history).ok();
+    Ok(history)
+}
+
+// handle loading the messages of one thread
+async fn handle_load_thread(
+    socket: SocketRef,
+    data: LoadThreadData,
+    app_state: State<AppState>,
+) -> Result<ThreadData, ChatError> {
+    let viewer = app_state.get_socket_identity(&socket.id.to_string()).await;
+    if !app_state.can_read_room(viewer.as_ref(), &data.room).await {
+        return Err(ChatError::NotInRoom(data.room));
     }
+    let thread = app_state.get_thread(&data.room, &data.thread_id).await?;
+    socket.emit("thread_messages", &thread).ok();
+    Ok(thread)
 }
 
-// handle user leaving the room
-async fn handle_leave_room(socket: SocketRef, data: JoinRoomData, app_state: State<AppState>) {
+// handle a direct message to another user
+async fn handle_send_direct_message(
+    socket: SocketRef,
+    data: SendDirectMessageData,
+    app_state: State<AppState>,
+) -> Result<DirectMessageData, ChatError> {
     let socket_id = socket.id.to_string();
-    info!("User {} requested to leave room: {}", data.username, data.room);
+    let identity = app_state
+        .get_socket_identity(&socket_id)
+        .await
+        .ok_or(ChatError::Unauthenticated)?;
+    let recipient = app_state.resolve_account(&data.to).await?;
+    if recipient.id == identity.account_id {
+        return Err(ChatError::ValidationFailed(
+            "you can't message yourself".to_string(),
+        ));
+    }
+    let room = dm_room(&identity.account_id, &recipient.id);
+    app_state
+        .check_not_auto_muted(&room, &identity.account_id)
+        .await?;
+    let markup = app_state.render_markup(&data.message).await;
+    let message = ChatMessage::new(identity.username.clone(), data.message, room)
+        .with_author(identity.account_id.clone())
+        .with_markup(markup);
+    if let Err(e) = app_state.add_message(message.clone()).await {
+        error!(
+            "failed to store direct message from {}: {}",
+            identity.username, e
+        );
+        return Err(e.into());
+    }
+    info!(
+        "User {} sent a direct message to {}",
+        identity.username, recipient.username
+    );
 
-    // Remove user and get their info
-    if let Some(user) = app_state.remove_user(&socket_id).await {
-        info!("User {} left room: {}", user.username, user.room);
-
-        // get updated room users
-        let room_users = app_state.get_room_users(&user.room).await;
-        let user_count = room_users.len();
-        let usernames: Vec<String> = room_users.iter().map(|u| u.username.clone()).collect();
-
-        // send updated user list to remaining users in the room
-        if !room_users.is_empty() {
-            let room_users_data = RoomUsersData {
-                users: usernames,
-                count: user_count,
-            };
+    // deliver to every open socket of both participants
+    let direct_message = DirectMessageData {
+        from: identity.username,
+        to: recipient.username,
+        message,
+    };
+    socket
+        .within(user_room(&recipient.id))
+        .within(user_room(&identity.account_id))
+        .emit("direct_message", &direct_message)
+        .ok();
+    Ok(direct_message)
+}
 
-            socket
-                .to(user.room.to_owned())
-                .emit("room_users_updated", &room_users_data)
-                .ok();
+// handle loading the conversation with another user
+async fn handle_load_direct_messages(
+    socket: SocketRef,
+    data: LoadDirectMessagesData,
+    app_state: State<AppState>,
+) -> Result<DirectHistoryData, ChatError> {
+    let socket_id = socket.id.to_string();
+    let identity = app_state
+        .get_socket_identity(&socket_id)
+        .await
+        .ok_or(ChatError::Unauthenticated)?;
+    let other = app_state.resolve_account(&data.with).await?;
 
-            // notify other users that user left
-            let user_left_data = UserLeftData {
-                username: user.username.clone(),
-                room: user.room.clone(),
-                user_count,
-            };
+    let page = app_state
+        .get_history_page(
+            &dm_room(&identity.account_id, &other.id),
+            data.before.as_deref(),
+            data.limit,
+        )
+        .await;
+    let history = DirectHistoryData {
+        with: other.username,
+        messages: page.messages,
+        has_more: page.has_more,
+    };
+    socket.emit("direct_messages", &history).ok();
+    Ok(history)
+}
 
-            socket
-                .to(user.room.clone())
-                .emit("user_left", &user_left_data)
-                .ok();
+// handle  typing indicator
 
-            // create and broadcast system message
-            let system_message = ChatMessage {
-                id: uuid::Uuid::new_v4().to_string(),
-                username: "System".to_string(),
-                message: format!("{} has left the room.", user.username),
-                room: user.room.clone(),
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
-            app_state.add_message(system_message.clone()).await;
-            socket
-                .to(user.room.to_owned())
-                .emit("new_message", &system_message)
-                .ok();
-        }
+async fn handle_typing(
+    socket: SocketRef,
+    data: TypingRoomData,
+    app_state: State<AppState>,
+    is_typing: bool,
+) -> Result<(), ChatError> {
+    let socket_id = socket.id.to_string();
+    let user = app_state.require_member(&socket_id, &data.room).await?;
+    app_state
+        .check_not_muted(&data.room, &user.account_id)
+        .await?;
 
-        // Update rooms list for all clients
-        let rooms_info = app_state.get_rooms_info().await;
-        socket.broadcast().emit("rooms_list", &rooms_info).ok();
-    }
+    let typing_data = TypingData {
+        username: user.username.clone(),
+        room: user.room.clone(),
+        is_typing,
+    };
+
+    // broadcast typing status to others in the room  (excluding sender)
+    socket.to(user.room).emit("user_typing", &typing_data).ok();
+    Ok(())
+}
+
+// handle user leaving one room
+async fn handle_leave_room(
+    socket: SocketRef,
+    data: LeaveRoomData,
+    app_state: State<AppState>,
+) -> Result<(), ChatError> {
+    let socket_id = socket.id.to_string();
+    info!(
+        "Socket {} requested to leave room: {}",
+        socket_id, data.room
+    );
+
+    // Remove user and get their info
+    let user = app_state
+        .remove_user_from_room(&socket_id, &data.room)
+        .await
+        .ok_or_else(|| ChatError::NotInRoom(data.room.clone()))?;
+    socket.leave(data.room).ok();
+    announce_user_left(&socket, &app_state, &user).await;
+
+    // Update rooms list for all clients
+    broadcast_rooms_list(&socket, &app_state).await;
+    Ok(())
 }
 
 // Handle user disconnect
@@ -283,68 +916,270 @@ async fn handle_leave_room(socket: SocketRef, data: JoinRoomData, app_state: Sta
 async fn handle_disconnect(socket: SocketRef, app_state: State<AppState>) {
     let socket_id = socket.id.to_string();
     info!("Client disconnected: {}", socket_id);
+    app_state.remove_socket_identity(&socket_id).await;
+    app_state.forget_rate_limits(&socket_id).await;
 
-    // Remove user and get their info
-    if let Some(user) = app_state.remove_user(&socket_id).await {
-        info!("User {} left room: {}", user.username, user.room);
-
-        // get updated room users
-        let room_users = app_state.get_room_users(&user.room).await;
-        let user_count = room_users.len();
-        let usernames: Vec<String> = room_users.iter().map(|u| u.username.clone()).collect();
-
-        // send updated  user list to  remaining users in the room
-        if !room_users.is_empty() {
-            let room_users_data = RoomUsersData {
-                users: usernames,
-                count: user_count,
-            };
+    // Remove user from every room they joined
+    let users = app_state.remove_user(&socket_id).await;
+    if users.is_empty() {
+        return;
+    }
+    for user in &users {
+        announce_user_left(&socket, &app_state, user).await;
+    }
 
-            socket
-                .to(user.room.to_owned())
-                .emit("room_users_updated", &room_users_data)
-                .ok();
+    // Update rooms list for all clients
+    broadcast_rooms_list(&socket, &app_state).await;
+}
 
-            // notify other user that user left
-            let user_left_data = UserLeftData {
-                username: user.username.clone(),
-                room: user.room.clone(),
-                user_count,
-            };
+// tell the rest of a room that a user left it
+async fn announce_user_left(socket: &SocketRef, app_state: &AppState, user: &User) {
+    info!("User {} left room: {}", user.username, user.room);
 
-            socket
-                .to(user.room.clone())
-                .emit("user_left", &user_left_data)
-                .ok();
+    // get updated room users
+    let usernames = app_state.get_room_usernames(&user.room).await;
+    let user_count = usernames.len();
 
-            //create and broadcast system message
-            let system_message = ChatMessage {
-                id: uuid::Uuid::new_v4().to_string(),
-                username: "System".to_string(),
-                message: format!("{} has left the room.", user.username),
-                room: user.room.clone(),
-                timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
-            app_state.add_message(system_message.clone()).await;
-            socket
-                .to(user.room.to_owned())
-                .emit("new_message", &system_message)
-                .ok();
-        }
+    // send updated user list to remaining users in the room
+    if usernames.is_empty() {
+        return;
+    }
+    let room_users_data = RoomUsersData {
+        room: user.room.clone(),
+        users: usernames,
+        count: user_count,
+    };
+
+    socket
+        .to(user.room.to_owned())
+        .emit("room_users_updated", &room_users_data)
+        .ok();
+
+    // notify other users that user left
+    let user_left_data = UserLeftData {
+        username: user.username.clone(),
+        room: user.room.clone(),
+        user_count,
+    };
+
+    socket
+        .to(user.room.clone())
+        .emit("user_left", &user_left_data)
+        .ok();
+
+    // create and broadcast system message
+    let system_message = ChatMessage::system(
+        format!("{} has left the room.", user.username),
+        user.room.clone(),
+    );
+    if let Err(e) = app_state.add_message(system_message.clone()).await {
+        error!("failed to store system message: {}", e);
+    }
+    socket
+        .to(user.room.to_owned())
+        .emit("new_message", &system_message)
+        .ok();
+}
 
-        // Update rooms list for all clients
-        let rooms_info = app_state.get_rooms_info().await;
-        socket.broadcast().emit("rooms_list", &rooms_info).ok();
+// send a client the rooms it is allowed to see
+async fn emit_rooms_list(socket: &SocketRef, app_state: &AppState) {
+    let viewer = app_state.get_socket_identity(&socket.id.to_string()).await;
+    let rooms_info = app_state.get_rooms_info(viewer.as_ref()).await;
+    socket.emit("rooms_list", &rooms_info).ok();
+}
+
+// send the room list to every client, each one only sees the private rooms it belongs to
+async fn broadcast_rooms_list(socket: &SocketRef, app_state: &AppState) {
+    let mut sockets = socket.broadcast().sockets().unwrap_or_default();
+    sockets.push(socket.clone());
+    for socket in sockets {
+        emit_rooms_list(&socket, app_state).await;
     }
 }
 
-// Handle getting room list
+// handle marking mentions as read, acks the mentions still unread
+async fn handle_mark_mentions_read(
+    socket: SocketRef,
+    data: MarkMentionsReadData,
+    app_state: State<AppState>,
+) -> Result<Vec<MentionNotification>, ChatError> {
+    let Some(identity) = app_state.get_socket_identity(&socket.id.to_string()).await else {
+        return Err(ChatError::Unauthenticated);
+    };
+    app_state
+        .mark_mentions_read(
+            &identity.account_id,
+            data.ids.as_deref(),
+            data.room.as_deref(),
+        )
+        .await;
+    Ok(app_state.unread_mentions(&identity.account_id).await)
+}
+
+// Handle getting the unread mentions of the `Authorization: Bearer` user
+pub async fn get_unread_mentions(
+    app_state: State<AppState>,
+    headers: axum::http::HeaderMap,
+) -> Result<axum::Json<Vec<MentionNotification>>, ChatError> {
+    let identity = request_identity(&app_state, &headers)
+        .await
+        .ok_or(ChatError::Unauthenticated)?;
+    let mentions = app_state.unread_mentions(&identity.account_id).await;
+    Ok(axum::Json(mentions))
+}
+
+// Handle getting room list, private rooms are included for the `Authorization: Bearer` user
 pub async fn get_rooms_list(
     app_state: State<AppState>,
-) -> Result<axum::Json<HashMap<String, usize>>, axum::http::StatusCode> {
-    let rooms = app_state.get_rooms_info().await;
+    headers: axum::http::HeaderMap,
+) -> Result<axum::Json<Vec<RoomInfo>>, axum::http::StatusCode> {
+    let viewer = request_identity(&app_state, &headers).await;
+    let rooms = app_state.get_rooms_info(viewer.as_ref()).await;
     Ok(axum::Json(rooms))
 }
+
+// Handle getting a page of room history
+pub async fn get_room_history(
+    app_state: State<AppState>,
+    headers: axum::http::HeaderMap,
+    mut room: String,
+    query: HistoryQuery,
+) -> Result<axum::Json<HistoryPage>, ChatError> {
+    normalize_room_name(&mut room)?;
+    let viewer = request_identity(&app_state, &headers).await;
+    if !app_state.can_read_room(viewer.as_ref(), &room).await {
+        return Err(ChatError::NotInRoom(room));
+    }
+    let history = app_state
+        .get_history_page(&room, query.before.as_deref(), query.limit)
+        .await;
+    Ok(axum::Json(history))
+}
+
+// Handle account registration
+pub async fn register(
+    app_state: State<AppState>,
+    request: AuthRequest,
+) -> Result<(axum::http::StatusCode, axum::Json<AuthResponse>), ChatError> {
+    let account = app_state
+        .accounts
+        .register(request.username.trim(), &request.password)
+        .await
+        .map_err(|e| {
+            warn!("registration failed for {}: {}", request.username, e);
+            ChatError::from(e)
+        })?;
+    info!("Registered account {} ({})", account.username, account.id);
+
+    let response = start_session(&app_state, &account.id, &account.username).await?;
+    Ok((axum::http::StatusCode::CREATED, axum::Json(response)))
+}
+
+// Handle login with username and password
+pub async fn login(
+    app_state: State<AppState>,
+    request: AuthRequest,
+) -> Result<axum::Json<AuthResponse>, ChatError> {
+    let account = app_state
+        .accounts
+        .verify_credentials(request.username.trim(), &request.password)
+        .await
+        .map_err(|e| {
+            warn!("login failed for {}: {}", request.username, e);
+            ChatError::from(e)
+        })?;
+
+    let response = start_session(&app_state, &account.id, &account.username).await?;
+    Ok(axum::Json(response))
+}
+
+// Handle logout, ending the session of the bearer token
+pub async fn logout(
+    app_state: State<AppState>,
+    headers: axum::http::HeaderMap,
+) -> Result<axum::http::StatusCode, ChatError> {
+    let token = bearer_token(&headers).ok_or(ChatError::Unauthenticated)?;
+    let claims = app_state.token_auth.verify(token)?;
+    match app_state.accounts.end_session(&claims.jti).await {
+        Ok(true) => Ok(axum::http::StatusCode::NO_CONTENT),
+        Ok(false) => Err(ChatError::Unauthenticated),
+        Err(e) => {
+            error!("failed to end session {}: {}", claims.jti, e);
+            Err(e.into())
+        }
+    }
+}
+
+// Handle getting the server metrics
+pub async fn get_metrics(app_state: State<AppState>) -> axum::Json<MetricsSnapshot> {
+    axum::Json(app_state.metrics.snapshot().await)
+}
+
+// issue a token and record its session
+async fn start_session(
+    app_state: &AppState,
+    account_id: &str,
+    username: &str,
+) -> Result<AuthResponse, ChatError> {
+    let (token, claims) = app_state
+        .token_auth
+        .issue(account_id, username)
+        .map_err(|e| {
+            error!("failed to issue token for {}: {}", username, e);
+            ChatError::Internal(e.to_string())
+        })?;
+
+    let session = Session {
+        id: claims.jti,
+        account_id: account_id.to_string(),
+        created_at: claims.iat,
+        expires_at: claims.exp,
+    };
+    if let Err(e) = app_state.accounts.create_session(session).await {
+        error!("failed to store session for {}: {}", username, e);
+        return Err(e.into());
+    }
+
+    Ok(AuthResponse {
+        account_id: account_id.to_string(),
+        username: username.to_string(),
+        token,
+        expires_at: claims.exp,
+    })
+}
+
+// Handle redeeming an invite code for the `Authorization: Bearer` user, who can then join the room
+pub async fn redeem_invite_code(
+    app_state: State<AppState>,
+    headers: axum::http::HeaderMap,
+    code: String,
+) -> Result<axum::Json<InviteCode>, ChatError> {
+    let identity = request_identity(&app_state, &headers)
+        .await
+        .ok_or(ChatError::Unauthenticated)?;
+    let invite = app_state.redeem_invite_code(&identity, &code).await?;
+    info!(
+        "User {} redeemed an invite code for room {}",
+        identity.username, invite.room
+    );
+    Ok(axum::Json(invite))
+}
+
+// the identity of the `Authorization: Bearer` token, if any and valid
+async fn request_identity(
+    app_state: &AppState,
+    headers: &axum::http::HeaderMap,
+) -> Option<Identity> {
+    let token = bearer_token(headers)?;
+    app_state.authenticate_token(token).await.ok()
+}
+
+// extract the token from an `Authorization: Bearer <token>` header
+fn bearer_token(headers: &axum::http::HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
@@ -0,0 +1,99 @@
+use crate::errors::ChatError;
+use crate::models::{Invitation, InviteCode};
+use std::collections::{HashMap, HashSet};
+
+// How long an invite code stays valid when no expiry is requested
+pub const DEFAULT_INVITE_CODE_TTL_SECS: u64 = 24 * 60 * 60;
+// Longest expiry that can be requested for an invite code
+pub const MAX_INVITE_CODE_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+// Pending invitations, invite codes and the accounts they let into rooms
+#[derive(Debug, Default)]
+pub struct InviteBook {
+    // invitation_id -> Invitation
+    invitations: HashMap<String, Invitation>,
+    // code -> InviteCode
+    codes: HashMap<String, InviteCode>,
+    // room_name -> accounts that accepted an invitation or redeemed a code
+    grants: HashMap<String, HashSet<String>>,
+}
+
+impl InviteBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // record an invitation, an earlier one for the same room and account is replaced
+    pub fn add_invitation(&mut self, invitation: Invitation) {
+        self.invitations
+            .retain(|_, i| i.room != invitation.room || i.to_id != invitation.to_id);
+        self.invitations.insert(invitation.id.clone(), invitation);
+    }
+
+    // remove an invitation addressed to `account_id`
+    pub fn take_invitation(
+        &mut self,
+        invitation_id: &str,
+        account_id: &str,
+    ) -> Result<Invitation, ChatError> {
+        match self.invitations.get(invitation_id) {
+            Some(invitation) if invitation.to_id == account_id => {
+                Ok(self.invitations.remove(invitation_id).unwrap())
+            }
+            _ => Err(ChatError::InvitationNotFound(invitation_id.to_string())),
+        }
+    }
+
+    // invitations waiting for an answer from an account, oldest first
+    pub fn pending_for(&self, account_id: &str) -> Vec<Invitation> {
+        let mut pending: Vec<Invitation> = self
+            .invitations
+            .values()
+            .filter(|i| i.to_id == account_id)
+            .cloned()
+            .collect();
+        pending.sort_by_key(|i| i.created_at);
+        pending
+    }
+
+    pub fn add_code(&mut self, code: InviteCode) {
+        self.codes.insert(code.code.clone(), code);
+    }
+
+    // count one use of a code, codes that expired or ran out of uses are dropped
+    pub fn redeem_code(&mut self, code: &str, now: u64) -> Result<InviteCode, ChatError> {
+        self.codes
+            .retain(|_, c| c.expires_at.is_none_or(|at| at > now));
+        let invite = self
+            .codes
+            .get_mut(code)
+            .ok_or(ChatError::InvalidInviteCode)?;
+        invite.uses += 1;
+        let invite = invite.clone();
+        if invite.max_uses.is_some_and(|max| invite.uses >= max) {
+            self.codes.remove(code);
+        }
+        Ok(invite)
+    }
+
+    // let an account into a room regardless of its access mode
+    pub fn grant(&mut self, room_name: &str, account_id: &str) {
+        self.grants
+            .entry(room_name.to_string())
+            .or_default()
+            .insert(account_id.to_string());
+    }
+
+    pub fn is_granted(&self, room_name: &str, account_id: &str) -> bool {
+        self.grants
+            .get(room_name)
+            .is_some_and(|accounts| accounts.contains(account_id))
+    }
+
+    // drop everything about a room that no longer exists
+    pub fn forget_room(&mut self, room_name: &str) {
+        self.invitations.retain(|_, i| i.room != room_name);
+        self.codes.retain(|_, c| c.room != room_name);
+        self.grants.remove(room_name);
+    }
+}
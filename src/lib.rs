@@ -1,7 +1,35 @@
+pub mod accounts;
+pub mod auth;
+pub mod errors;
+pub mod filters;
+pub mod invites;
+pub mod markup;
+pub mod metrics;
 pub mod models;
+pub mod moderation;
+pub mod notifications;
+pub mod ratelimit;
+pub mod retention;
+pub mod rooms;
 pub mod state;
+pub mod store;
+pub mod validation;
 pub mod handlers;
 
+pub use accounts::*;
+pub use auth::*;
+pub use errors::*;
+pub use filters::*;
+pub use invites::*;
+pub use markup::*;
+pub use metrics::*;
 pub use models::*;
+pub use moderation::*;
+pub use notifications::*;
+pub use ratelimit::*;
+pub use retention::*;
+pub use rooms::*;
 pub use state::*;
+pub use store::*;
+pub use validation::*;
 pub use handlers::*;
\ No newline at end of file
@@ -1,14 +1,27 @@
-use std::{sync::Arc, thread};
+use std::{net::SocketAddr, sync::Arc, thread};
 
-use axum::{response::Html, routing::get, Router};
+use axum::{
+    extract::{Path, Query},
+    http::HeaderMap,
+    response::Html,
+    routing::{get, post},
+    Json, Router,
+};
 use hyper::StatusCode;
-use rust_socket_chat::{get_rooms_list, on_connect, AppState};
+use rust_socket_chat::{
+    authenticate, edit_window_from_env, get_metrics, get_room_history, get_rooms_list,
+    get_unread_mentions, login, logout, moderators_from_env, on_connect, persistent_rooms_from_env,
+    redeem_invite_code, register, room_retention_from_env, sweep_interval_from_env, AccountStore,
+    AppState, AuthPayload, AuthRequest, FileMessageStore, FilterChain, HistoryQuery,
+    InMemoryMessageStore, MessageStore, RateLimitConfig, RetentionPolicy, RoomStore, TokenAuth,
+};
 use socketioxide::{
-    extract::{SocketRef, State},
+    extract::{SocketRef, State, TryData},
+    handler::ConnectHandler,
     SocketIo,
 };
 use tower_http::cors::{Any, CorsLayer};
-use tracing::{info, Level};
+use tracing::{error, info, Level};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -20,21 +33,103 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Starting rust socket.io chat server...");
 
+    // pick the message history backend
+    let store_kind = std::env::var("MESSAGE_STORE").unwrap_or_else(|_| "memory".to_string());
+    let message_store: Arc<dyn MessageStore> = match store_kind.as_str() {
+        "file" => {
+            let path = std::env::var("MESSAGE_LOG_PATH")
+                .unwrap_or_else(|_| "data/messages.log".to_string());
+            info!("Using file message store at {}", path);
+            Arc::new(FileMessageStore::open(path).await?)
+        }
+        "memory" => {
+            info!("Using in-memory message store");
+            Arc::new(InMemoryMessageStore::new())
+        }
+        other => return Err(format!("unknown MESSAGE_STORE: {}", other).into()),
+    };
+
     // create app state
-    let app_state = Arc::new(AppState::new());
+    let retention = RetentionPolicy::from_env();
+    info!("Default history retention: {:?}", retention);
+    let room_retention = room_retention_from_env()?;
+    for (room, policy) in &room_retention {
+        info!("History retention of room {}: {:?}", room, policy);
+    }
+    let token_auth = TokenAuth::from_env()?;
+    let accounts_path =
+        std::env::var("ACCOUNTS_PATH").unwrap_or_else(|_| "data/accounts.json".to_string());
+    let account_store = if accounts_path.is_empty() {
+        info!("Using in-memory account store");
+        AccountStore::in_memory()
+    } else {
+        info!("Using account store at {}", accounts_path);
+        AccountStore::open(accounts_path).await?
+    };
+    let rooms_path = std::env::var("ROOMS_PATH").unwrap_or_else(|_| "data/rooms.json".to_string());
+    let room_store = if rooms_path.is_empty() {
+        info!("Persistent rooms are kept in memory only");
+        RoomStore::in_memory()
+    } else {
+        info!("Using room store at {}", rooms_path);
+        RoomStore::open(rooms_path)
+    };
+    let rate_limits = RateLimitConfig::from_env()?;
+    let app_state = Arc::new(
+        AppState::with_message_store(message_store)
+            .with_default_retention(retention)
+            .with_room_retention(room_retention)
+            .with_edit_window(edit_window_from_env())
+            .with_moderators(moderators_from_env())
+            .with_token_auth(token_auth)
+            .with_account_store(account_store)
+            .with_room_store(room_store)
+            .with_rate_limits(rate_limits)
+            .with_message_filters(FilterChain::from_env()),
+    );
+    let room_count = app_state.load_rooms(persistent_rooms_from_env()).await?;
+    info!("{} persistent rooms open", room_count);
+
+    // periodically drop history that aged out of its retention policy, and rooms nobody joined
+    tokio::spawn({
+        let app_state = app_state.clone();
+        async move {
+            let mut interval = tokio::time::interval(sweep_interval_from_env());
+            loop {
+                interval.tick().await;
+                match app_state.sweep_history().await {
+                    Ok(0) => {}
+                    Ok(removed) => info!("Retention sweep removed {} messages", removed),
+                    Err(e) => error!("Retention sweep failed: {}", e),
+                }
+                match app_state.sweep_unjoined_rooms().await {
+                    0 => {}
+                    removed => info!("Dropped {} rooms nobody joined", removed),
+                }
+            }
+        }
+    });
 
     // create socket io layer
     let (layer, io) = SocketIo::new_layer();
 
-    // Register the main namespace handler
+    // Register the main namespace handler, sockets must pass token authentication first
     io.ns("/", {
         let app_state = app_state.clone(); // typically Arc<AppState>
-        move |socket: SocketRef| {
+        let handler = {
             let app_state = app_state.clone();
-            async move {
-                on_connect(socket, State((*app_state).clone())).await; // <-- Correct type
+            move |socket: SocketRef| {
+                let app_state = app_state.clone();
+                async move {
+                    on_connect(socket, State((*app_state).clone())).await; // <-- Correct type
+                }
             }
-        }
+        };
+        let auth_middleware = move |socket: SocketRef, TryData(auth): TryData<AuthPayload>| {
+            let app_state = app_state.clone();
+            async move { authenticate(socket, auth.ok(), State((*app_state).clone())).await }
+        };
+        handler.with(auth_middleware)
     });
 
     // Create CORS layer
@@ -51,7 +146,64 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "/api/rooms",
             get({
                 let app_state = app_state.clone();
-                move || get_rooms_list(State((*app_state).clone()))
+                move |headers: HeaderMap| get_rooms_list(State((*app_state).clone()), headers)
+            }),
+        )
+        .route(
+            "/api/auth/register",
+            post({
+                let app_state = app_state.clone();
+                move |Json(request): Json<AuthRequest>| {
+                    register(State((*app_state).clone()), request)
+                }
+            }),
+        )
+        .route(
+            "/api/auth/login",
+            post({
+                let app_state = app_state.clone();
+                move |Json(request): Json<AuthRequest>| login(State((*app_state).clone()), request)
+            }),
+        )
+        .route(
+            "/api/auth/logout",
+            post({
+                let app_state = app_state.clone();
+                move |headers: HeaderMap| logout(State((*app_state).clone()), headers)
+            }),
+        )
+        .route(
+            "/api/metrics",
+            get({
+                let app_state = app_state.clone();
+                move || get_metrics(State((*app_state).clone()))
+            }),
+        )
+        .route(
+            "/api/notifications/mentions",
+            get({
+                let app_state = app_state.clone();
+                move |headers: HeaderMap| get_unread_mentions(State((*app_state).clone()), headers)
+            }),
+        )
+        .route(
+            "/api/invites/:code/redeem",
+            post({
+                let app_state = app_state.clone();
+                move |headers: HeaderMap, Path(code): Path<String>| {
+                    redeem_invite_code(State((*app_state).clone()), headers, code)
+                }
+            }),
+        )
+        .route(
+            "/api/rooms/:room/messages",
+            get({
+                let app_state = app_state.clone();
+                move |headers: HeaderMap,
+                      Path(room): Path<String>,
+                      Query(query): Query<HistoryQuery>| {
+                    get_room_history(State((*app_state).clone()), headers, room, query)
+                }
             }),
         )
         .layer(cors)
@@ -64,7 +216,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server = format!("0.0.0.0:{}", port);
     // Start the server
     let listener = tokio::net::TcpListener::bind(server).await?;
-    axum::serve(listener, app).await?;
+    // keep the peer address, the rate limits are also per IP
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
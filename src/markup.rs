@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+// Characters that can't end a link, mention or room reference, e.g. "see #rust."
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', '!', '?', ';', ':', ')', ']', '}', '\'', '"'];
+// Characters that can't appear in a `#room` reference
+const ROOM_REF_STOP: &[char] = &['/', '\\', '#', '@', ':'];
+
+// A piece of a parsed message. Clients render the tokens in order instead of the raw text;
+// styled runs hold plain text only, they don't nest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Token {
+    Text {
+        text: String,
+    },
+    // **bold**
+    Bold {
+        text: String,
+    },
+    // *italic* or _italic_
+    Italic {
+        text: String,
+    },
+    // ~~strike~~
+    Strike {
+        text: String,
+    },
+    // `code`
+    Code {
+        text: String,
+    },
+    // ```code block```
+    CodeBlock {
+        text: String,
+    },
+    // http(s) URL
+    Link {
+        url: String,
+    },
+    // @username, only kept for existing accounts
+    Mention {
+        username: String,
+        #[serde(default)]
+        account_id: Option<String>,
+    },
+    // #room, only kept for existing rooms
+    RoomRef {
+        room: String,
+    },
+}
+
+// split a message into tokens. Mentions and room references are not resolved here,
+// unknown ones are turned back into text by `AppState::render_markup`
+pub fn parse(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    // code blocks first, nothing inside them is parsed
+    while let Some(start) = rest.find("```") {
+        let Some(len) = rest[start + 3..].find("```") else {
+            break;
+        };
+        parse_inline(&rest[..start], &mut tokens);
+        let code = &rest[start + 3..start + 3 + len];
+        tokens.push(Token::CodeBlock {
+            text: code.strip_prefix('\n').unwrap_or(code).to_string(),
+        });
+        rest = &rest[start + 3 + len + 3..];
+    }
+    parse_inline(rest, &mut tokens);
+    merge_text(tokens)
+}
+
+// join neighbouring text tokens
+pub fn merge_text(tokens: Vec<Token>) -> Vec<Token> {
+    let mut merged: Vec<Token> = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        match (merged.last_mut(), token) {
+            (_, Token::Text { text }) if text.is_empty() => {}
+            (Some(Token::Text { text: last }), Token::Text { text }) => last.push_str(&text),
+            (_, token) => merged.push(token),
+        }
+    }
+    merged
+}
+
+// the original text of a mention or room reference that didn't resolve
+pub fn as_text(token: &Token) -> Option<String> {
+    match token {
+        Token::Mention { username, .. } => Some(format!("@{}", username)),
+        Token::RoomRef { room } => Some(format!("#{}", room)),
+        _ => None,
+    }
+}
+
+fn parse_inline(text: &str, tokens: &mut Vec<Token>) {
+    let chars: Vec<char> = text.chars().collect();
+    let mut plain = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some((token, next)) = token_at(&chars, i) {
+            if !plain.is_empty() {
+                tokens.push(Token::Text {
+                    text: std::mem::take(&mut plain),
+                });
+            }
+            tokens.push(token);
+            i = next;
+        } else {
+            plain.push(chars[i]);
+            i += 1;
+        }
+    }
+    if !plain.is_empty() {
+        tokens.push(Token::Text { text: plain });
+    }
+}
+
+// the token starting at `i` and the index after it, if any
+fn token_at(chars: &[char], i: usize) -> Option<(Token, usize)> {
+    let at_word_start = i == 0 || {
+        let prev = chars[i - 1];
+        prev.is_whitespace() || matches!(prev, '(' | '[' | '{' | '"' | '\'')
+    };
+    match chars[i] {
+        '`' => delimited(chars, i, "`").map(|(text, next)| (Token::Code { text }, next)),
+        '*' if starts_with(chars, i, "**") => {
+            delimited(chars, i, "**").map(|(text, next)| (Token::Bold { text }, next))
+        }
+        '~' if starts_with(chars, i, "~~") => {
+            delimited(chars, i, "~~").map(|(text, next)| (Token::Strike { text }, next))
+        }
+        '*' => delimited(chars, i, "*").map(|(text, next)| (Token::Italic { text }, next)),
+        // snake_case words are not italic
+        '_' if at_word_start => {
+            let (text, next) = delimited(chars, i, "_")?;
+            let ends_word = chars.get(next).is_none_or(|c| !c.is_alphanumeric());
+            ends_word.then_some((Token::Italic { text }, next))
+        }
+        '@' if at_word_start => {
+            let (username, next) = word(chars, i + 1, |c| {
+                c.is_ascii_alphanumeric() || c == '_' || c == '-'
+            })?;
+            Some((
+                Token::Mention {
+                    username,
+                    account_id: None,
+                },
+                next,
+            ))
+        }
+        '#' if at_word_start => {
+            let (room, next) = word(chars, i + 1, |c| {
+                !c.is_whitespace() && !c.is_control() && !ROOM_REF_STOP.contains(&c)
+            })?;
+            Some((Token::RoomRef { room }, next))
+        }
+        'h' if at_word_start
+            && (starts_with(chars, i, "http://") || starts_with(chars, i, "https://")) =>
+        {
+            let (url, next) = word(chars, i, |c| !c.is_whitespace())?;
+            let scheme_only = url == "http://" || url == "https://";
+            (!scheme_only).then_some((Token::Link { url }, next))
+        }
+        _ => None,
+    }
+}
+
+fn starts_with(chars: &[char], i: usize, prefix: &str) -> bool {
+    prefix
+        .chars()
+        .enumerate()
+        .all(|(k, c)| chars.get(i + k) == Some(&c))
+}
+
+// text between `delim` at `i` and the next `delim` on the same line; it can't be empty
+// or start or end with a space
+fn delimited(chars: &[char], i: usize, delim: &str) -> Option<(String, usize)> {
+    let len = delim.chars().count();
+    let start = i + len;
+    let mut end = start;
+    while end < chars.len() && chars[end] != '\n' {
+        if starts_with(chars, end, delim) {
+            let inner: String = chars[start..end].iter().collect();
+            if inner.is_empty() || inner.starts_with(' ') || inner.ends_with(' ') {
+                return None;
+            }
+            return Some((inner, end + len));
+        }
+        end += 1;
+    }
+    None
+}
+
+// the longest run of `allowed` characters from `start`, without trailing punctuation
+fn word(chars: &[char], start: usize, allowed: impl Fn(char) -> bool) -> Option<(String, usize)> {
+    let mut end = start;
+    while end < chars.len() && allowed(chars[end]) {
+        end += 1;
+    }
+    while end > start && TRAILING_PUNCTUATION.contains(&chars[end - 1]) {
+        end -= 1;
+    }
+    (end > start).then(|| (chars[start..end].iter().collect(), end))
+}
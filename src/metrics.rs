@@ -0,0 +1,39 @@
+use crate::errors::ChatError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::sync::RwLock as TokioRwLock;
+
+// Snapshot of the counters, served by `/api/metrics`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetricsSnapshot {
+    // error_code -> count
+    pub errors: BTreeMap<String, u64>,
+    // event name -> failed requests
+    pub errors_by_event: BTreeMap<String, u64>,
+}
+
+// In-process counters
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: TokioRwLock<MetricsSnapshot>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // count a failed client request
+    pub async fn record_error(&self, event: &str, error: &ChatError) {
+        let mut counters = self.counters.write().await;
+        *counters.errors.entry(error.code().to_string()).or_insert(0) += 1;
+        *counters
+            .errors_by_event
+            .entry(event.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub async fn snapshot(&self) -> MetricsSnapshot {
+        self.counters.read().await.clone()
+    }
+}
@@ -1,14 +1,97 @@
+use crate::accounts::Account;
+use crate::auth::Identity;
+use crate::markup::Token;
+use crate::retention::RetentionPolicy;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
+    // stable account id, shared by every socket of the same account
+    pub account_id: String,
     pub username: String,
     pub room: String,
     pub socket_id: String,
 }
 
+// A chat room and its metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Room {
+    pub name: String,
+    #[serde(default)]
+    pub topic: String,
+    #[serde(default)]
+    pub description: String,
+    // username and account of whoever created the room, None for rooms created by the server
+    pub creator: Option<String>,
+    pub creator_id: Option<String>,
+    pub created_at: u64,
+    // kept, with its history, when the last user leaves
+    #[serde(default)]
+    pub persistent: bool,
+    #[serde(default)]
+    pub settings: RoomSettings,
+    // account_id -> role, accounts without an entry are plain members
+    #[serde(default)]
+    pub roles: HashMap<String, RoomRole>,
+    // argon2 hash of the password of a password protected room, never sent to clients
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+}
+
+// Privilege of an account in one room, ordered from least to most privileged
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomRole {
+    #[default]
+    Member,
+    // can delete messages of others and kick members
+    Moderator,
+    // can also change the room settings and the roles of others
+    Owner,
+}
+
+// Who can join a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomAccess {
+    // anyone
+    #[default]
+    Public,
+    // anyone who knows the room password, hidden from the room list of everyone else
+    Password,
+    // only invited users, also hidden from the room list
+    InviteOnly,
+}
+
+// Per-room settings, changed with `update_room`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomSettings {
+    #[serde(default)]
+    pub access: RoomAccess,
+    // most users allowed in the room at once, None for no limit
+    #[serde(default)]
+    pub max_users: Option<usize>,
+    // history retention override, None uses the server default
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    // refuse messages containing links
+    #[serde(default)]
+    pub block_links: bool,
+}
+
+// A room as listed in `rooms_list` and `/api/rooms`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    #[serde(flatten)]
+    pub room: Room,
+    pub user_count: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: String,
@@ -16,18 +99,381 @@ pub struct ChatMessage {
     pub message: String,
     pub room: String,
     pub timestamp: u64,
+    // author account, None for system messages
+    #[serde(default)]
+    pub account_id: Option<String>,
+    // when the text was last edited
+    #[serde(default)]
+    pub edited_at: Option<u64>,
+    // previous versions of the text, oldest first
+    #[serde(default)]
+    pub revisions: Vec<MessageRevision>,
+    // tombstone: the content was removed by its author or a moderator
+    #[serde(default)]
+    pub deleted: bool,
+    // emoji reactions, in the order they were first added
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // the message this one answers
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    // id of the thread root, set on every reply of a thread
+    #[serde(default)]
+    pub thread_id: Option<String>,
+    // number of replies in the thread, on the root message only
+    #[serde(default)]
+    pub reply_count: usize,
+    // the parsed text, empty for system messages and messages stored before markup
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    // accounts @mentioned in the text
+    #[serde(default)]
+    pub mentions: Vec<Mention>,
+}
+
+// An account @mentioned in a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    pub account_id: String,
+    pub username: String,
+}
+
+// Tells an account it was @mentioned in a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MentionNotification {
+    pub id: String,
+    pub room: String,
+    pub message_id: String,
+    // username and account of the author
+    pub from: String,
+    pub from_id: String,
+    // start of the message text
+    pub excerpt: String,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+// `mark_mentions_read` payload: the given ids, else every mention in `room`, else all
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkMentionsReadData {
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,
+    #[serde(default)]
+    pub room: Option<String>,
+}
+
+// Everyone who reacted to a message with one emoji
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    // usernames of the reactors
+    pub users: Vec<String>,
+}
+
+// A replaced version of a message text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRevision {
+    pub message: String,
+    // when this version was written
+    pub timestamp: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JoinRoomData {
     pub room: String,
+    // ignored on join, the server uses the username of the session
+    #[serde(default)]
+    pub username: String,
+    // needed for password protected rooms, never echoed back
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateRoomData {
+    pub name: String,
+    #[serde(default)]
+    pub topic: String,
+    #[serde(default)]
+    pub description: String,
+    // only moderators can create persistent rooms
+    #[serde(default)]
+    pub persistent: bool,
+    #[serde(default)]
+    pub settings: RoomSettings,
+    // required when `settings.access` is "password"
+    pub password: Option<String>,
+}
+
+// Fields left out are not changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateRoomData {
+    pub room: String,
+    pub topic: Option<String>,
+    pub description: Option<String>,
+    // only moderators can change whether a room is persistent
+    pub persistent: Option<bool>,
+    pub settings: Option<RoomSettings>,
+    // new password, required when switching a room to "password" access
+    pub password: Option<String>,
+}
+
+// An invitation to a room, waiting for the invited user to accept or decline it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: String,
+    pub room: String,
+    // username and account of the inviter
+    pub from: String,
+    pub from_id: String,
+    // username and account of the invited user
+    pub to: String,
+    pub to_id: String,
+    pub created_at: u64,
+}
+
+// A shareable code that lets whoever redeems it into a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteCode {
+    pub code: String,
+    pub room: String,
+    // account that created the code
+    pub created_by: String,
+    pub created_at: u64,
+    // None for a code that never expires
+    pub expires_at: Option<u64>,
+    // None for unlimited uses
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteUserData {
+    pub room: String,
+    // username or account id of the user to invite
+    pub to: String,
+}
+
+// `accept_invite` / `decline_invite` payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvitationResponseData {
+    pub invitation_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateInviteCodeData {
+    pub room: String,
+    // defaults to a day, 0 for a code that never expires
+    pub expires_in_secs: Option<u64>,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrantRoleData {
+    pub room: String,
+    // username or account id
+    pub to: String,
+    pub role: RoomRole,
+}
+
+// takes a user back to a plain member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokeRoleData {
+    pub room: String,
+    // username or account id
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleChangedData {
+    pub room: String,
+    pub username: String,
+    pub account_id: String,
+    pub role: RoomRole,
+    // username of whoever changed the role
+    pub by: String,
+}
+
+// A ban or mute of an account in a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sanction {
+    pub account_id: String,
     pub username: String,
+    // username of the moderator
+    pub by: String,
+    pub reason: Option<String>,
+    pub created_at: u64,
+    // None until lifted by a moderator
+    pub until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+}
+
+// `kick_user` / `ban_user` / `unban_user` / `mute_user` / `unmute_user` payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationData {
+    pub room: String,
+    // username or account id
+    pub to: String,
+    // bans and mutes only, None until lifted
+    pub duration_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+// A moderation action, sent to the room and to the affected user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationActionData {
+    pub room: String,
+    pub action: ModerationAction,
+    pub username: String,
+    pub account_id: String,
+    pub by: String,
+    pub reason: Option<String>,
+    // end of a ban or mute
+    pub until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaveRoomData {
+    pub room: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SendMessageData {
     pub message: String,
     pub room: String,
+    // id of the message being answered, starts or continues its thread
+    #[serde(default)]
+    pub reply_to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditMessageData {
+    pub room: String,
+    pub message_id: String,
+    // the new text
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteMessageData {
+    pub room: String,
+    pub message_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionData {
+    pub room: String,
+    pub message_id: String,
+    pub emoji: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionsUpdatedData {
+    pub room: String,
+    pub message_id: String,
+    pub reactions: Vec<Reaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadThreadData {
+    pub room: String,
+    pub thread_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadData {
+    pub room: String,
+    pub root: ChatMessage,
+    // oldest first
+    pub replies: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadUpdatedData {
+    pub room: String,
+    pub thread_id: String,
+    pub reply_count: usize,
+    pub last_reply: ChatMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendDirectMessageData {
+    // username or account id of the recipient
+    pub to: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadDirectMessagesData {
+    // username or account id of the other participant
+    pub with: String,
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// A direct message as delivered to both participants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessageData {
+    // usernames of the sender and the recipient
+    pub from: String,
+    pub to: String,
+    pub message: ChatMessage,
+}
+
+// One page of the conversation with another user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectHistoryData {
+    // username of the other participant
+    pub with: String,
+    pub messages: Vec<ChatMessage>,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadHistoryData {
+    pub room: String,
+    // id of the oldest message the client already has
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub room: String,
+    // oldest first
+    pub messages: Vec<ChatMessage>,
+    // whether older messages exist before this page
+    pub has_more: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthResponse {
+    pub account_id: String,
+    pub username: String,
+    pub token: String,
+    pub expires_at: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,10 +492,44 @@ pub struct UserLeftData {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomUsersData {
+    pub room: String,
     pub users: Vec<String>,
     pub count: usize,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinErrorData {
+    pub room: String,
+    // machine readable reason, e.g. "room_full"
+    pub code: String,
+    pub reason: String,
+}
+
+// Result of a client event, sent back through the Socket.IO ack
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckResponse {
+    pub ok: bool,
+    pub error_code: Option<String>,
+    // handler result on success, `{ message, ... }` on failure
+    pub data: Option<serde_json::Value>,
+}
+
+// Structured `error` event pushed to the client when one of its events fails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorData {
+    // the client event that failed
+    pub event: String,
+    pub error_code: String,
+    // `{ message, ... }`, same as the ack data
+    pub data: serde_json::Value,
+}
+
+// `typing` / `stop_typing` payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypingRoomData {
+    pub room: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TypingData {
     pub username: String,
@@ -57,6 +537,108 @@ pub struct TypingData {
     pub is_typing: bool,
 }
 
+impl Room {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            topic: String::new(),
+            description: String::new(),
+            creator: None,
+            creator_id: None,
+            persistent: false,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            settings: RoomSettings::default(),
+            roles: HashMap::new(),
+            password_hash: None,
+        }
+    }
+
+    // record the account that created the room, it becomes the owner
+    pub fn with_creator(mut self, account_id: String, username: String) -> Self {
+        self.roles.insert(account_id.clone(), RoomRole::Owner);
+        self.creator_id = Some(account_id);
+        self.creator = Some(username);
+        self
+    }
+
+    pub fn role_of(&self, account_id: &str) -> RoomRole {
+        self.roles.get(account_id).copied().unwrap_or_default()
+    }
+
+    // whether the room is written to the room store: persistent rooms, and private ad-hoc
+    // rooms so a restart never leaves their history readable as an unknown, public room
+    pub fn is_saved(&self) -> bool {
+        self.persistent || self.settings.access != RoomAccess::Public
+    }
+}
+
+impl Invitation {
+    pub fn new(room: String, from: &Identity, to: &Account) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            room,
+            from: from.username.clone(),
+            from_id: from.account_id.clone(),
+            to: to.username.clone(),
+            to_id: to.id.clone(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+}
+
+impl InviteCode {
+    // None if the expiry doesn't fit in a timestamp
+    pub fn new(
+        room: String,
+        created_by: String,
+        expires_in_secs: Option<u64>,
+        max_uses: Option<u32>,
+    ) -> Option<Self> {
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let expires_at = match expires_in_secs {
+            Some(secs) => Some(created_at.checked_add(secs)?),
+            None => None,
+        };
+        Some(Self {
+            code: Uuid::new_v4().simple().to_string(),
+            room,
+            created_by,
+            created_at,
+            expires_at,
+            max_uses,
+            uses: 0,
+        })
+    }
+}
+
+impl MentionNotification {
+    pub fn new(message: &ChatMessage, excerpt_len: usize) -> Self {
+        let mut excerpt: String = message.message.chars().take(excerpt_len).collect();
+        if excerpt.len() < message.message.len() {
+            excerpt.push('…');
+        }
+        Self {
+            id: Uuid::new_v4().to_string(),
+            room: message.room.clone(),
+            message_id: message.id.clone(),
+            from: message.username.clone(),
+            from_id: message.account_id.clone().unwrap_or_default(),
+            excerpt,
+            created_at: message.timestamp,
+            read: false,
+        }
+    }
+}
+
 impl ChatMessage {
     pub fn new(username: String, message: String, room: String) -> Self {
         Self {
@@ -68,14 +650,104 @@ impl ChatMessage {
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
+            account_id: None,
+            edited_at: None,
+            revisions: Vec::new(),
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: None,
+            thread_id: None,
+            reply_count: 0,
+            tokens: Vec::new(),
+            mentions: Vec::new(),
         }
     }
+
+    // message generated by the server, e.g. join/leave notices
+    pub fn system(message: String, room: String) -> Self {
+        Self::new("System".to_string(), message, room)
+    }
+
+    // drop the content but keep the id, author and timestamp
+    pub fn tombstone(&mut self) {
+        self.message.clear();
+        self.revisions.clear();
+        self.reactions.clear();
+        self.tokens.clear();
+        self.mentions.clear();
+        self.deleted = true;
+    }
+
+    // add a user to the reactors of an emoji, returns false if they already reacted
+    pub fn add_reaction(&mut self, emoji: &str, username: &str) -> bool {
+        let position = self.reactions.iter().position(|r| r.emoji == emoji);
+        let reaction = match position {
+            Some(i) => &mut self.reactions[i],
+            None => {
+                self.reactions.push(Reaction {
+                    emoji: emoji.to_string(),
+                    count: 0,
+                    users: Vec::new(),
+                });
+                self.reactions.last_mut().unwrap()
+            }
+        };
+        if reaction.users.iter().any(|u| u == username) {
+            return false;
+        }
+        reaction.users.push(username.to_string());
+        reaction.count = reaction.users.len();
+        true
+    }
+
+    // remove a user from the reactors of an emoji, returns false if they hadn't reacted
+    pub fn remove_reaction(&mut self, emoji: &str, username: &str) -> bool {
+        let Some(i) = self.reactions.iter().position(|r| r.emoji == emoji) else {
+            return false;
+        };
+        let reaction = &mut self.reactions[i];
+        let Some(j) = reaction.users.iter().position(|u| u == username) else {
+            return false;
+        };
+        reaction.users.remove(j);
+        reaction.count = reaction.users.len();
+        if reaction.users.is_empty() {
+            self.reactions.remove(i);
+        }
+        true
+    }
+
+    // make this message a reply to `parent`, joining the parent's thread
+    pub fn in_reply_to(mut self, parent: &ChatMessage) -> Self {
+        self.reply_to = Some(parent.id.clone());
+        self.thread_id = Some(
+            parent
+                .thread_id
+                .clone()
+                .unwrap_or_else(|| parent.id.clone()),
+        );
+        self
+    }
+
+    // attach the account that wrote the message
+    pub fn with_author(mut self, account_id: String) -> Self {
+        self.account_id = Some(account_id);
+        self
+    }
+
+    // attach the parsed text, see `AppState::render_markup`
+    pub fn with_markup(mut self, (tokens, mentions): (Vec<Token>, Vec<Mention>)) -> Self {
+        self.tokens = tokens;
+        self.mentions = mentions;
+        self
+    }
 }
 
 impl User {
-    pub fn new(username: String, room: String, socket_id: String) -> Self {
+    pub fn new(account_id: String, username: String, room: String, socket_id: String) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
+            account_id,
             username,
             room,
             socket_id,
@@ -0,0 +1,84 @@
+use crate::models::Sanction;
+use std::collections::HashMap;
+
+// Active bans and mutes, per room
+#[derive(Debug, Default)]
+pub struct ModerationBook {
+    // room_name -> account_id -> ban
+    bans: HashMap<String, HashMap<String, Sanction>>,
+    // room_name -> account_id -> mute
+    mutes: HashMap<String, HashMap<String, Sanction>>,
+}
+
+impl ModerationBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ban(&mut self, room_name: &str, sanction: Sanction) {
+        insert(&mut self.bans, room_name, sanction);
+    }
+
+    // lift a ban, returns false if there was none
+    pub fn unban(&mut self, room_name: &str, account_id: &str) -> bool {
+        remove(&mut self.bans, room_name, account_id)
+    }
+
+    // the ban of an account in a room, expired bans are dropped
+    pub fn active_ban(&mut self, room_name: &str, account_id: &str, now: u64) -> Option<Sanction> {
+        active(&mut self.bans, room_name, account_id, now)
+    }
+
+    pub fn mute(&mut self, room_name: &str, sanction: Sanction) {
+        insert(&mut self.mutes, room_name, sanction);
+    }
+
+    // lift a mute, returns false if there was none
+    pub fn unmute(&mut self, room_name: &str, account_id: &str) -> bool {
+        remove(&mut self.mutes, room_name, account_id)
+    }
+
+    // the mute of an account in a room, expired mutes are dropped
+    pub fn active_mute(&mut self, room_name: &str, account_id: &str, now: u64) -> Option<Sanction> {
+        active(&mut self.mutes, room_name, account_id, now)
+    }
+}
+
+fn insert(
+    book: &mut HashMap<String, HashMap<String, Sanction>>,
+    room_name: &str,
+    sanction: Sanction,
+) {
+    book.entry(room_name.to_string())
+        .or_default()
+        .insert(sanction.account_id.clone(), sanction);
+}
+
+fn remove(
+    book: &mut HashMap<String, HashMap<String, Sanction>>,
+    room_name: &str,
+    account_id: &str,
+) -> bool {
+    let Some(sanctions) = book.get_mut(room_name) else {
+        return false;
+    };
+    let removed = sanctions.remove(account_id).is_some();
+    if sanctions.is_empty() {
+        book.remove(room_name);
+    }
+    removed
+}
+
+fn active(
+    book: &mut HashMap<String, HashMap<String, Sanction>>,
+    room_name: &str,
+    account_id: &str,
+    now: u64,
+) -> Option<Sanction> {
+    let sanction = book.get(room_name)?.get(account_id)?.clone();
+    if sanction.until.is_some_and(|until| until <= now) {
+        remove(book, room_name, account_id);
+        return None;
+    }
+    Some(sanction)
+}
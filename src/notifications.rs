@@ -0,0 +1,66 @@
+use crate::models::MentionNotification;
+use std::collections::HashMap;
+
+// Notifications kept per account, the oldest are dropped past this
+pub const MAX_MENTIONS_PER_ACCOUNT: usize = 200;
+
+// Mention notifications of every account, oldest first
+#[derive(Debug, Default)]
+pub struct NotificationBook {
+    // account_id -> notifications
+    mentions: HashMap<String, Vec<MentionNotification>>,
+}
+
+impl NotificationBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_mention(&mut self, account_id: &str, notification: MentionNotification) {
+        let mentions = self.mentions.entry(account_id.to_string()).or_default();
+        mentions.push(notification);
+        if mentions.len() > MAX_MENTIONS_PER_ACCOUNT {
+            let excess = mentions.len() - MAX_MENTIONS_PER_ACCOUNT;
+            mentions.drain(..excess);
+        }
+    }
+
+    // mentions of an account not marked as read yet, oldest first
+    pub fn unread_mentions(&self, account_id: &str) -> Vec<MentionNotification> {
+        self.mentions
+            .get(account_id)
+            .map(|mentions| mentions.iter().filter(|m| !m.read).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // mark mentions as read: the given ids, or every mention in `room`, or all of them.
+    // returns how many were unread
+    pub fn mark_read(
+        &mut self,
+        account_id: &str,
+        ids: Option<&[String]>,
+        room: Option<&str>,
+    ) -> usize {
+        let Some(mentions) = self.mentions.get_mut(account_id) else {
+            return 0;
+        };
+        let mut marked = 0;
+        for mention in mentions.iter_mut().filter(|m| !m.read) {
+            let selected = ids.is_none_or(|ids| ids.contains(&mention.id))
+                && room.is_none_or(|room| mention.room == room);
+            if selected {
+                mention.read = true;
+                marked += 1;
+            }
+        }
+        marked
+    }
+
+    // drop the notifications of a deleted message
+    pub fn forget_message(&mut self, room: &str, message_id: &str) {
+        for mentions in self.mentions.values_mut() {
+            mentions.retain(|m| m.room != room || m.message_id != message_id);
+        }
+        self.mentions.retain(|_, mentions| !mentions.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// Escalation: rejections within the strike window before a mute or a disconnect
+const DEFAULT_STRIKE_WINDOW_SECS: u64 = 60;
+const DEFAULT_MUTE_AFTER: u32 = 20;
+const DEFAULT_MUTE_SECS: u64 = 5 * 60;
+const DEFAULT_DISCONNECT_AFTER: u32 = 50;
+
+// `burst` events, refilled evenly over `per_secs` seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_secs: u64,
+}
+
+impl Rate {
+    pub const fn new(burst: u32, per_secs: u64) -> Self {
+        Self { burst, per_secs }
+    }
+}
+
+// parses `burst/per_secs`, e.g. `5/10` for 5 events every 10 seconds
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, per_secs) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected burst/seconds, got {:?}", s))?;
+        let burst: u32 = burst
+            .trim()
+            .parse()
+            .map_err(|_| format!("bad burst in {:?}", s))?;
+        let per_secs: u64 = per_secs
+            .trim()
+            .parse()
+            .map_err(|_| format!("bad seconds in {:?}", s))?;
+        if burst == 0 || per_secs == 0 {
+            return Err(format!("burst and seconds must be positive in {:?}", s));
+        }
+        Ok(Self { burst, per_secs })
+    }
+}
+
+// The limits of one event, per socket, per account and per client IP. `None` means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventLimits {
+    pub socket: Option<Rate>,
+    pub account: Option<Rate>,
+    pub ip: Option<Rate>,
+}
+
+impl EventLimits {
+    // parses `socket,account,ip`, each a rate or `-` for no limit, e.g. `5/5,10/5,-`
+    fn parse(s: &str) -> Result<Self, String> {
+        let mut rates = s.split(',').map(|rate| match rate.trim() {
+            "" | "-" => Ok(None),
+            rate => rate.parse().map(Some),
+        });
+        let mut next = || rates.next().unwrap_or(Ok(None));
+        Ok(Self {
+            socket: next()?,
+            account: next()?,
+            ip: next()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    // event -> limits
+    pub events: HashMap<String, EventLimits>,
+    // limits of events not listed in `events`
+    pub default: EventLimits,
+    // rejections older than this are forgotten
+    pub strike_window_secs: u64,
+    // mute the account after this many rejections in the window, 0 never mutes
+    pub mute_after: u32,
+    pub mute_secs: u64,
+    // disconnect the socket after this many rejections in the window, 0 never disconnects
+    pub disconnect_after: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let events = [
+            (
+                "send_message",
+                EventLimits {
+                    socket: Some(Rate::new(5, 5)),
+                    account: Some(Rate::new(10, 5)),
+                    ip: Some(Rate::new(30, 5)),
+                },
+            ),
+            (
+                "send_direct_message",
+                EventLimits {
+                    socket: Some(Rate::new(5, 5)),
+                    account: Some(Rate::new(10, 5)),
+                    ip: Some(Rate::new(30, 5)),
+                },
+            ),
+            (
+                "typing",
+                EventLimits {
+                    socket: Some(Rate::new(10, 10)),
+                    account: Some(Rate::new(20, 10)),
+                    ip: None,
+                },
+            ),
+            (
+                "stop_typing",
+                EventLimits {
+                    socket: Some(Rate::new(10, 10)),
+                    account: Some(Rate::new(20, 10)),
+                    ip: None,
+                },
+            ),
+        ];
+        Self {
+            events: events
+                .into_iter()
+                .map(|(event, limits)| (event.to_string(), limits))
+                .collect(),
+            default: EventLimits {
+                socket: Some(Rate::new(20, 10)),
+                account: Some(Rate::new(40, 10)),
+                ip: Some(Rate::new(100, 10)),
+            },
+            strike_window_secs: DEFAULT_STRIKE_WINDOW_SECS,
+            mute_after: DEFAULT_MUTE_AFTER,
+            mute_secs: DEFAULT_MUTE_SECS,
+            disconnect_after: DEFAULT_DISCONNECT_AFTER,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    // read overrides from the environment:
+    // `RATE_LIMIT_<EVENT>` (e.g. `RATE_LIMIT_SEND_MESSAGE=5/5,10/5,30/5`) and `RATE_LIMIT_DEFAULT`
+    // set the socket, account and ip limits of an event; `RATE_LIMIT_STRIKE_WINDOW_SECS`,
+    // `RATE_LIMIT_MUTE_AFTER`, `RATE_LIMIT_MUTE_SECS` and `RATE_LIMIT_DISCONNECT_AFTER`
+    // tune the escalation
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+        for (key, value) in std::env::vars() {
+            let Some(event) = key.strip_prefix("RATE_LIMIT_") else {
+                continue;
+            };
+            match event {
+                "STRIKE_WINDOW_SECS" => config.strike_window_secs = parse_env(&key, &value)?,
+                "MUTE_AFTER" => config.mute_after = parse_env(&key, &value)?,
+                "MUTE_SECS" => config.mute_secs = parse_env(&key, &value)?,
+                "DISCONNECT_AFTER" => config.disconnect_after = parse_env(&key, &value)?,
+                _ => {
+                    let limits =
+                        EventLimits::parse(&value).map_err(|e| format!("{}: {}", key, e))?;
+                    if event == "DEFAULT" {
+                        config.default = limits;
+                    } else {
+                        config.events.insert(event.to_lowercase(), limits);
+                    }
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn limits(&self, event: &str) -> &EventLimits {
+        self.events.get(event).unwrap_or(&self.default)
+    }
+}
+
+fn parse_env<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("{}: invalid value {:?}", key, value))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let per_sec = rate.burst as f64 / rate.per_secs as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(rate.burst as f64);
+        self.updated = now;
+    }
+
+    // seconds until a token is available, 0 if there is one now
+    fn wait_secs(&self, rate: Rate) -> f64 {
+        let per_sec = rate.burst as f64 / rate.per_secs as f64;
+        ((1.0 - self.tokens) / per_sec).max(0.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Socket(String),
+    Account(String),
+    Ip(IpAddr),
+}
+
+// Who sent an event
+#[derive(Debug, Clone, Copy)]
+pub struct Caller<'a> {
+    pub socket_id: &'a str,
+    pub account_id: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+}
+
+// What happens to an event that went over its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+    // only this event is refused
+    None,
+    // the account was just muted for `secs` seconds
+    Mute { secs: u64 },
+    // the socket should be disconnected
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limited {
+    pub retry_after_secs: u64,
+    pub penalty: Penalty,
+}
+
+// Token buckets for every (event, socket/account/ip), plus the rejections used to escalate
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<(String, BucketKey), TokenBucket>,
+    // caller -> times of its recent rejections, counted per account when there is one so
+    // reconnecting doesn't start over
+    strikes: HashMap<BucketKey, VecDeque<Instant>>,
+    // account_id -> end of an automatic mute
+    mutes: HashMap<String, Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    // take a token from every bucket of the event, or none if one of them is empty
+    pub fn check(&mut self, event: &str, caller: Caller, now: Instant) -> Result<(), Limited> {
+        let limits = *self.config.limits(event);
+        let scopes = [
+            (
+                limits.socket,
+                Some(BucketKey::Socket(caller.socket_id.to_string())),
+            ),
+            (
+                limits.account,
+                caller
+                    .account_id
+                    .map(|id| BucketKey::Account(id.to_string())),
+            ),
+            (limits.ip, caller.ip.map(BucketKey::Ip)),
+        ];
+        let scopes: Vec<(Rate, BucketKey)> = scopes
+            .into_iter()
+            .filter_map(|(rate, key)| Some((rate?, key?)))
+            .collect();
+
+        let mut wait: f64 = 0.0;
+        for (rate, key) in &scopes {
+            let bucket = self
+                .buckets
+                .entry((event.to_string(), key.clone()))
+                .or_insert_with(|| TokenBucket::full(*rate, now));
+            bucket.refill(*rate, now);
+            wait = wait.max(bucket.wait_secs(*rate));
+        }
+        if wait <= 0.0 {
+            for (_, key) in scopes {
+                if let Some(bucket) = self.buckets.get_mut(&(event.to_string(), key)) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+            return Ok(());
+        }
+
+        Err(Limited {
+            retry_after_secs: wait.ceil() as u64,
+            penalty: self.strike(caller, now),
+        })
+    }
+
+    // record a rejection and escalate when the caller keeps going
+    fn strike(&mut self, caller: Caller, now: Instant) -> Penalty {
+        let window = Duration::from_secs(self.config.strike_window_secs);
+        let key = match caller.account_id {
+            Some(account_id) => BucketKey::Account(account_id.to_string()),
+            None => BucketKey::Socket(caller.socket_id.to_string()),
+        };
+        let strikes = self.strikes.entry(key).or_default();
+        while strikes
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > window)
+        {
+            strikes.pop_front();
+        }
+        strikes.push_back(now);
+        let count = strikes.len() as u32;
+
+        if self.config.disconnect_after > 0 && count >= self.config.disconnect_after {
+            return Penalty::Disconnect;
+        }
+        if self.config.mute_after > 0 && count == self.config.mute_after {
+            if let Some(account_id) = caller.account_id {
+                let until = now + Duration::from_secs(self.config.mute_secs);
+                self.mutes.insert(account_id.to_string(), until);
+                return Penalty::Mute {
+                    secs: self.config.mute_secs,
+                };
+            }
+        }
+        Penalty::None
+    }
+
+    // seconds left of an automatic mute of the account
+    pub fn muted_for(&self, account_id: &str, now: Instant) -> Option<u64> {
+        let until = self.mutes.get(account_id)?;
+        let left = until.saturating_duration_since(now);
+        (!left.is_zero()).then(|| left.as_secs().max(1))
+    }
+
+    // drop the state of a disconnected socket, and buckets that filled up again and strikes
+    // that left the window
+    pub fn forget_socket(&mut self, socket_id: &str, now: Instant) {
+        let window = Duration::from_secs(self.config.strike_window_secs);
+        self.strikes.retain(|key, strikes| {
+            !matches!(key, BucketKey::Socket(id) if id == socket_id)
+                && strikes
+                    .back()
+                    .is_some_and(|at| now.saturating_duration_since(*at) <= window)
+        });
+        let config = &self.config;
+        self.buckets.retain(|(event, key), bucket| {
+            if matches!(key, BucketKey::Socket(id) if id == socket_id) {
+                return false;
+            }
+            let limits = config.limits(event);
+            let rate = match key {
+                BucketKey::Socket(_) => limits.socket,
+                BucketKey::Account(_) => limits.account,
+                BucketKey::Ip(_) => limits.ip,
+            };
+            rate.is_some_and(|rate| {
+                bucket.refill(rate, now);
+                bucket.tokens < rate.burst as f64
+            })
+        });
+        self.mutes.retain(|_, until| *until > now);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::models::ChatMessage;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+// Default limits, used when the matching environment variable is not set
+const DEFAULT_MAX_MESSAGES: usize = 1000;
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 60;
+
+// How much history a room keeps. `None` means no limit for that dimension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub max_messages: Option<usize>,
+    pub max_age_secs: Option<u64>,
+    pub max_bytes: Option<usize>,
+}
+
+impl RetentionPolicy {
+    // read the default policy from the environment, a value of 0 disables a limit
+    pub fn from_env() -> Self {
+        Self {
+            max_messages: env_limit("RETENTION_MAX_MESSAGES", DEFAULT_MAX_MESSAGES),
+            max_age_secs: env_limit("RETENTION_MAX_AGE_SECS", 0),
+            max_bytes: env_limit("RETENTION_MAX_BYTES", DEFAULT_MAX_BYTES),
+        }
+    }
+
+    // this policy without any limit looser than the ones of `limit`
+    pub fn capped_by(&self, limit: &RetentionPolicy) -> Self {
+        Self {
+            max_messages: cap(self.max_messages, limit.max_messages),
+            max_age_secs: cap(self.max_age_secs, limit.max_age_secs),
+            max_bytes: cap(self.max_bytes, limit.max_bytes),
+        }
+    }
+
+    // number of oldest messages that must be dropped to satisfy the policy.
+    // `messages` is a room history ordered oldest first.
+    pub fn excess(&self, messages: &[ChatMessage], now: u64) -> usize {
+        let mut drop = 0;
+
+        if let Some(max_messages) = self.max_messages {
+            drop = drop.max(messages.len().saturating_sub(max_messages));
+        }
+
+        if let Some(max_age) = self.max_age_secs {
+            let expired = messages
+                .iter()
+                .take_while(|m| now.saturating_sub(m.timestamp) > max_age)
+                .count();
+            drop = drop.max(expired);
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            // walk from the newest message and keep as many as fit
+            let mut total = 0;
+            let kept = messages
+                .iter()
+                .rev()
+                .take_while(|m| {
+                    total += message_size(m);
+                    total <= max_bytes
+                })
+                .count();
+            drop = drop.max(messages.len() - kept);
+        }
+
+        drop
+    }
+}
+
+// read per-room overrides from `RETENTION_ROOMS`: `room=max_messages,max_age_secs,max_bytes`
+// entries separated by `;`, e.g. `general=500,0,0;logs=0,86400,0`, where 0 disables a limit
+pub fn room_retention_from_env() -> Result<HashMap<String, RetentionPolicy>, String> {
+    std::env::var("RETENTION_ROOMS")
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_room_retention)
+        .collect()
+}
+
+fn parse_room_retention(entry: &str) -> Result<(String, RetentionPolicy), String> {
+    let invalid = || {
+        format!(
+            "RETENTION_ROOMS: expected room=max_messages,max_age_secs,max_bytes, got {:?}",
+            entry
+        )
+    };
+    let (room, limits) = entry.rsplit_once('=').ok_or_else(invalid)?;
+    let limits: Vec<&str> = limits.split(',').map(str::trim).collect();
+    let [max_messages, max_age_secs, max_bytes] = limits[..] else {
+        return Err(invalid());
+    };
+    Ok((
+        room.trim().to_string(),
+        RetentionPolicy {
+            max_messages: parse_limit(max_messages).ok_or_else(invalid)?,
+            max_age_secs: parse_limit(max_age_secs).ok_or_else(invalid)?,
+            max_bytes: parse_limit(max_bytes).ok_or_else(invalid)?,
+        },
+    ))
+}
+
+// `Some(None)` for 0 (no limit), `None` if the value isn't a number
+fn parse_limit<T: FromStr + Default + PartialEq>(value: &str) -> Option<Option<T>> {
+    let value: T = value.parse().ok()?;
+    Some((value != T::default()).then_some(value))
+}
+
+// the tighter of two limits, `None` being no limit
+fn cap<T: Ord>(value: Option<T>, limit: Option<T>) -> Option<T> {
+    match (value, limit) {
+        (Some(value), Some(limit)) => Some(value.min(limit)),
+        (value, None) => value,
+        (None, limit) => limit,
+    }
+}
+
+// how often the background sweeper enforces retention on every room
+pub fn sweep_interval_from_env() -> Duration {
+    let secs = std::env::var("RETENTION_SWEEP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_SWEEP_INTERVAL_SECS);
+    Duration::from_secs(secs)
+}
+
+// bytes a message counts against `max_bytes`
+fn message_size(message: &ChatMessage) -> usize {
+    message.username.len() + message.message.len()
+}
+
+fn env_limit<T: FromStr + Default + PartialEq>(key: &str, default: T) -> Option<T> {
+    let value = std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default);
+    (value != T::default()).then_some(value)
+}
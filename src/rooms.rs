@@ -0,0 +1,92 @@
+use crate::models::Room;
+use serde::{Deserialize, Serialize};
+use std::{io, path::PathBuf};
+use tokio::sync::Mutex as TokioMutex;
+use tracing::info;
+
+// read the rooms to keep open at all times from `PERSISTENT_ROOMS`, comma separated
+pub fn persistent_rooms_from_env() -> Vec<String> {
+    std::env::var("PERSISTENT_ROOMS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// A room as saved on disk, `Room` itself never serializes its password hash
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredRoom {
+    #[serde(flatten)]
+    room: Room,
+    #[serde(default)]
+    password_hash: Option<String>,
+}
+
+// Persistent rooms, optionally saved to a JSON file. Public ad-hoc rooms only live in
+// `AppState`; private ones are written too, so their history can be dropped after a restart.
+#[derive(Debug, Default)]
+pub struct RoomStore {
+    path: Option<PathBuf>,
+    // serializes writes so an older snapshot never overwrites a newer one
+    write_lock: TokioMutex<()>,
+}
+
+impl RoomStore {
+    // store that forgets everything on restart
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    // rooms are read from and saved to a JSON file, created on the first save
+    pub fn open(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            write_lock: TokioMutex::new(()),
+        }
+    }
+
+    // read the saved rooms, none if the file doesn't exist yet
+    pub async fn load(&self) -> io::Result<Vec<Room>> {
+        let Some(path) = &self.path else {
+            return Ok(Vec::new());
+        };
+        let stored = match tokio::fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice::<Vec<StoredRoom>>(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let rooms: Vec<Room> = stored
+            .into_iter()
+            .map(|stored| Room {
+                password_hash: stored.password_hash,
+                ..stored.room
+            })
+            .collect();
+        info!("Loaded {} rooms from {}", rooms.len(), path.display());
+        Ok(rooms)
+    }
+
+    // replace the saved rooms (if file backed)
+    pub async fn save(&self, rooms: &[Room]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _guard = self.write_lock.lock().await;
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+        let stored: Vec<StoredRoom> = rooms
+            .iter()
+            .map(|room| StoredRoom {
+                password_hash: room.password_hash.clone(),
+                room: room.clone(),
+            })
+            .collect();
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(&stored)?).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+}
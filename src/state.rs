@@ -1,63 +1,383 @@
-use crate::models::{ChatMessage, User};
-use std::{collections::HashMap, sync::Arc};
+use crate::accounts::{hash_password, verify_password, Account, AccountStore};
+use crate::auth::{AuthError, Identity, TokenAuth};
+use crate::errors::ChatError;
+use crate::filters::{FilterChain, FilterContext};
+use crate::invites::{InviteBook, DEFAULT_INVITE_CODE_TTL_SECS};
+use crate::markup::{self, Token};
+use crate::metrics::Metrics;
+use crate::models::{
+    ChatMessage, CreateInviteCodeData, HistoryPage, Invitation, InviteCode, Mention,
+    MentionNotification, MessageRevision, ModerationAction, ModerationActionData, ModerationData,
+    RoleChangedData, Room, RoomAccess, RoomInfo, RoomRole, RoomSettings, Sanction, ThreadData,
+    UpdateRoomData, User,
+};
+use crate::moderation::ModerationBook;
+use crate::notifications::NotificationBook;
+use crate::ratelimit::{Caller, Limited, Penalty, RateLimitConfig, RateLimiter};
+use crate::retention::RetentionPolicy;
+use crate::rooms::RoomStore;
+use crate::store::{InMemoryMessageStore, MessageStore};
+use crate::validation::check_room_name;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::RwLock as TokioRwLock;
+use tracing::warn;
+
+// Number of messages sent on join and when no page size is requested
+pub const DEFAULT_HISTORY_PAGE_SIZE: usize = 50;
+// Upper bound for a requested page size
+pub const MAX_HISTORY_PAGE_SIZE: usize = 200;
+// Longest accepted reaction, in bytes (some emoji are several code points)
+pub const MAX_EMOJI_LEN: usize = 32;
+// How long after sending a message its author may still edit it
+pub const DEFAULT_EDIT_WINDOW_SECS: u64 = 15 * 60;
+// Characters of the message text kept in a mention notification
+pub const MENTION_EXCERPT_LEN: usize = 100;
+// How long an ad-hoc room made with `create_room` waits for its first user
+pub const UNJOINED_ROOM_TTL_SECS: u64 = 5 * 60;
+// Longest accepted room topic and description, in characters
+pub const MAX_TOPIC_LEN: usize = 200;
+pub const MAX_DESCRIPTION_LEN: usize = 2000;
+
+// Socket.IO room every socket of an account joins, used to reach that user directly
+pub fn user_room(account_id: &str) -> String {
+    format!("user:{}", account_id)
+}
+
+// History key of the conversation between two accounts, the same for both sides
+pub fn dm_room(account_a: &str, account_b: &str) -> String {
+    let (first, second) = if account_a <= account_b {
+        (account_a, account_b)
+    } else {
+        (account_b, account_a)
+    };
+    format!("dm:{}:{}", first, second)
+}
+
+// refuse topics and descriptions over their length limit
+pub fn check_room_metadata(topic: &str, description: &str) -> Result<(), ChatError> {
+    if topic.chars().count() > MAX_TOPIC_LEN {
+        return Err(ChatError::ValidationFailed(format!(
+            "topic must be at most {} characters",
+            MAX_TOPIC_LEN
+        )));
+    }
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(ChatError::ValidationFailed(format!(
+            "description must be at most {} characters",
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    Ok(())
+}
+
+// read the moderator usernames from `MODERATORS`, comma separated
+pub fn moderators_from_env() -> HashSet<String> {
+    std::env::var("MODERATORS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// read the edit window from `MESSAGE_EDIT_WINDOW_SECS`, 0 allows edits forever
+pub fn edit_window_from_env() -> Option<u64> {
+    let secs = std::env::var("MESSAGE_EDIT_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_EDIT_WINDOW_SECS);
+    (secs > 0).then_some(secs)
+}
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     // Users in rooms: room_name -> Vec<User>
     pub rooms: Arc<TokioRwLock<HashMap<String, Vec<User>>>>,
-    // Message history: room_name -> Vec<ChatMessage>
-    pub messages: Arc<TokioRwLock<HashMap<String, Vec<ChatMessage>>>>,
-    // Socket ID to User mapping
-    pub socket_users: Arc<TokioRwLock<HashMap<String, User>>>,
+    // Room metadata: room_name -> Room
+    pub room_meta: Arc<TokioRwLock<HashMap<String, Room>>>,
+    // Where persistent rooms are saved
+    pub room_store: Arc<RoomStore>,
+    // Pending invitations, invite codes and the accounts they let in
+    pub invites: Arc<TokioRwLock<InviteBook>>,
+    // Bans and mutes
+    pub moderation: Arc<TokioRwLock<ModerationBook>>,
+    // Mention notifications of every account
+    pub notifications: Arc<TokioRwLock<NotificationBook>>,
+    // Message history backend (in-memory or on-disk)
+    pub messages: Arc<dyn MessageStore>,
+    // History retention used for rooms without an override
+    pub default_retention: RetentionPolicy,
+    // How long authors may edit their messages, None for no limit
+    pub edit_window_secs: Option<u64>,
+    // Lowercased usernames allowed to moderate every room
+    pub moderators: Arc<HashSet<String>>,
+    // Per-room retention overrides: room_name -> RetentionPolicy
+    pub room_retention: Arc<TokioRwLock<HashMap<String, RetentionPolicy>>>,
+    // Per-room retention from the configuration, for rooms without an override
+    pub configured_retention: Arc<HashMap<String, RetentionPolicy>>,
+    // Rooms joined by each socket: socket_id -> room_name -> User
+    pub socket_users: Arc<TokioRwLock<HashMap<String, HashMap<String, User>>>>,
+    // Socket ID to verified identity, set during the handshake
+    pub socket_identities: Arc<TokioRwLock<HashMap<String, Identity>>>,
+    // Signs and verifies session tokens
+    pub token_auth: Arc<TokenAuth>,
+    // Registered accounts and their login sessions
+    pub accounts: Arc<AccountStore>,
+    // Error counters and other runtime metrics
+    pub metrics: Arc<Metrics>,
+    // Per socket, account and IP event limits
+    pub rate_limiter: Arc<TokioRwLock<RateLimiter>>,
+    // Run on every message sent to a room before it is stored
+    pub filters: Arc<FilterChain>,
 }
 
 impl AppState {
     pub fn new() -> Self {
+        Self::with_message_store(Arc::new(InMemoryMessageStore::new()))
+    }
+
+    // create state backed by the given message store
+    pub fn with_message_store(messages: Arc<dyn MessageStore>) -> Self {
         Self {
             rooms: Arc::new(TokioRwLock::new(HashMap::new())),
-            messages: Arc::new(TokioRwLock::new(HashMap::new())),
+            room_meta: Arc::new(TokioRwLock::new(HashMap::new())),
+            room_store: Arc::new(RoomStore::in_memory()),
+            invites: Arc::new(TokioRwLock::new(InviteBook::new())),
+            moderation: Arc::new(TokioRwLock::new(ModerationBook::new())),
+            notifications: Arc::new(TokioRwLock::new(NotificationBook::new())),
+            messages,
+            default_retention: RetentionPolicy::default(),
+            edit_window_secs: Some(DEFAULT_EDIT_WINDOW_SECS),
+            moderators: Arc::new(HashSet::new()),
+            room_retention: Arc::new(TokioRwLock::new(HashMap::new())),
+            configured_retention: Arc::new(HashMap::new()),
             socket_users: Arc::new(TokioRwLock::new(HashMap::new())),
+            socket_identities: Arc::new(TokioRwLock::new(HashMap::new())),
+            token_auth: Arc::new(TokenAuth::default()),
+            accounts: Arc::new(AccountStore::in_memory()),
+            metrics: Arc::new(Metrics::new()),
+            rate_limiter: Arc::new(TokioRwLock::new(RateLimiter::default())),
+            filters: Arc::new(FilterChain::new()),
         }
     }
+
+    // set the store used for accounts and sessions
+    pub fn with_account_store(mut self, accounts: AccountStore) -> Self {
+        self.accounts = Arc::new(accounts);
+        self
+    }
+
+    // set the store used for persistent rooms
+    pub fn with_room_store(mut self, room_store: RoomStore) -> Self {
+        self.room_store = Arc::new(room_store);
+        self
+    }
+
+    // set the event rate limits
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limiter = Arc::new(TokioRwLock::new(RateLimiter::new(config)));
+        self
+    }
+
+    // set the filters run on room messages
+    pub fn with_message_filters(mut self, filters: FilterChain) -> Self {
+        self.filters = Arc::new(filters);
+        self
+    }
+
+    // set the token signer used to authenticate sockets
+    pub fn with_token_auth(mut self, token_auth: TokenAuth) -> Self {
+        self.token_auth = Arc::new(token_auth);
+        self
+    }
+
+    // set the retention used by rooms without an override
+    pub fn with_default_retention(mut self, policy: RetentionPolicy) -> Self {
+        self.default_retention = policy;
+        self
+    }
+
+    // set the usernames allowed to moderate every room
+    pub fn with_moderators(mut self, moderators: HashSet<String>) -> Self {
+        self.moderators = Arc::new(moderators);
+        self
+    }
+
+    // whether a user is one of the `MODERATORS`, who act as owners of every room
+    pub fn is_server_moderator(&self, username: &str) -> bool {
+        self.moderators.contains(&username.to_lowercase())
+    }
+
+    // the role of an account in a room, members of unknown rooms are plain members
+    pub async fn room_role(&self, room_name: &str, account_id: &str, username: &str) -> RoomRole {
+        if self.is_server_moderator(username) {
+            return RoomRole::Owner;
+        }
+        let room_meta = self.room_meta.read().await;
+        room_meta
+            .get(room_name)
+            .map(|room| room.role_of(account_id))
+            .unwrap_or_default()
+    }
+
+    // fail with `forbidden` unless the account has at least `role` in the room
+    pub async fn require_role(
+        &self,
+        room_name: &str,
+        account_id: &str,
+        username: &str,
+        role: RoomRole,
+        action: &str,
+    ) -> Result<(), ChatError> {
+        if self.room_role(room_name, account_id, username).await >= role {
+            return Ok(());
+        }
+        let needed = match role {
+            RoomRole::Owner => "an owner",
+            RoomRole::Moderator => "a moderator",
+            RoomRole::Member => "a member",
+        };
+        Err(ChatError::Forbidden(format!(
+            "only {} of room {} can {}",
+            needed, room_name, action
+        )))
+    }
+
+    // set the retention of individual rooms, used unless a room sets its own
+    pub fn with_room_retention(mut self, configured: HashMap<String, RetentionPolicy>) -> Self {
+        self.configured_retention = Arc::new(configured);
+        self
+    }
+
+    // set how long authors may edit their messages
+    pub fn with_edit_window(mut self, edit_window_secs: Option<u64>) -> Self {
+        self.edit_window_secs = edit_window_secs;
+        self
+    }
+    // find the account a direct message is addressed to, by id or username
+    pub async fn resolve_account(&self, to: &str) -> Result<Account, ChatError> {
+        match self.accounts.get(to).await {
+            Some(account) => Ok(account),
+            None => self
+                .accounts
+                .find_by_username(to)
+                .await
+                .ok_or_else(|| ChatError::UnknownRecipient(to.to_string())),
+        }
+    }
+
     // add user to room
-    pub async fn add_user_to_room(&self, user: User) {
+    pub async fn add_user_to_room(&self, user: User) -> Result<(), ChatError> {
         let mut rooms = self.rooms.write().await;
         let mut socket_user = self.socket_users.write().await;
+        let mut room_meta = self.room_meta.write().await;
 
-        // remove user from previous room if exists
-        if let Some(old_user) = socket_user.get(&user.socket_id) {
-            if let Some(room_user) = rooms.get_mut(&old_user.room) {
-                room_user.retain(|u| u.socket_id != user.socket_id);
-                if room_user.is_empty() {
-                    rooms.remove(&old_user.room);
+        if let Some(room_users) = rooms.get(&user.room) {
+            // other sockets of an account already in the room don't count against the limit
+            let max_users = room_meta
+                .get(&user.room)
+                .and_then(|room| room.settings.max_users);
+            if let Some(max_users) = max_users {
+                let mut accounts: Vec<&str> =
+                    room_users.iter().map(|u| u.account_id.as_str()).collect();
+                accounts.sort_unstable();
+                accounts.dedup();
+                if !accounts.contains(&user.account_id.as_str()) && accounts.len() >= max_users {
+                    return Err(ChatError::RoomFull(user.room.clone()));
                 }
             }
         }
-        // add user to the new room
-        rooms
-            .entry(user.room.clone())
-            .or_insert_with(Vec::new)
-            .push(user.clone());
-        socket_user.insert(user.socket_id.clone(), user);
+
+        // joining a room that doesn't exist yet creates it
+        room_meta.entry(user.room.clone()).or_insert_with(|| {
+            Room::new(user.room.clone())
+                .with_creator(user.account_id.clone(), user.username.clone())
+        });
+
+        // add user to the new room, the socket stays in the rooms it already joined
+        let room_users = rooms.entry(user.room.clone()).or_insert_with(Vec::new);
+        room_users.retain(|u| u.socket_id != user.socket_id);
+        room_users.push(user.clone());
+        socket_user
+            .entry(user.socket_id.clone())
+            .or_default()
+            .insert(user.room.clone(), user);
+        Ok(())
+    }
+
+    // remove a socket from one room
+    pub async fn remove_user_from_room(&self, socket_id: &str, room_name: &str) -> Option<User> {
+        let mut rooms = self.rooms.write().await;
+        let mut socket_user = self.socket_users.write().await;
+        let mut room_meta = self.room_meta.write().await;
+        let mut invites = self.invites.write().await;
+
+        let socket_rooms = socket_user.get_mut(socket_id)?;
+        let user = socket_rooms.remove(room_name)?;
+        if socket_rooms.is_empty() {
+            socket_user.remove(socket_id);
+        }
+        let mut resave = false;
+        if let Some(room_users) = rooms.get_mut(room_name) {
+            room_users.retain(|u| u.socket_id != socket_id);
+            if room_users.is_empty() {
+                rooms.remove(room_name);
+                resave = remove_if_ad_hoc(
+                    &mut room_meta,
+                    &mut invites,
+                    self.messages.as_ref(),
+                    &self.room_retention,
+                    room_name,
+                )
+                .await;
+            }
+        }
+        drop((rooms, socket_user, room_meta, invites));
+        if resave {
+            self.save_rooms_or_warn().await;
+        }
+        Some(user)
     }
 
-    // remove user by socket id.
-    pub async fn remove_user(&self, socket_id: &str) -> Option<User> {
+    // remove a socket from every room it joined.
+    pub async fn remove_user(&self, socket_id: &str) -> Vec<User> {
         let mut rooms = self.rooms.write().await;
         let mut socket_user = self.socket_users.write().await;
+        let mut room_meta = self.room_meta.write().await;
+        let mut invites = self.invites.write().await;
 
-        if let Some(user) = socket_user.remove(socket_id) {
+        let users: Vec<User> = socket_user
+            .remove(socket_id)
+            .map(|socket_rooms| socket_rooms.into_values().collect())
+            .unwrap_or_default();
+        let mut resave = false;
+        for user in &users {
             if let Some(room_users) = rooms.get_mut(&user.room) {
                 room_users.retain(|u| u.socket_id != socket_id);
                 if room_users.is_empty() {
                     rooms.remove(&user.room);
+                    resave |= remove_if_ad_hoc(
+                        &mut room_meta,
+                        &mut invites,
+                        self.messages.as_ref(),
+                        &self.room_retention,
+                        &user.room,
+                    )
+                    .await;
                 }
             }
-            Some(user)
-        } else {
-            None
         }
+        drop((rooms, socket_user, room_meta, invites));
+        if resave {
+            self.save_rooms_or_warn().await;
+        }
+        users
     }
 
     // get users in a room
@@ -66,35 +386,1215 @@ impl AppState {
         rooms.get(room_name).cloned().unwrap_or_default()
     }
 
-    // get user by socket id
-    pub async fn get_user_by_socket_id(&self, socket_id: &str) -> Option<User> {
+    // get the distinct usernames in a room, an account with several sockets is listed once
+    pub async fn get_room_usernames(&self, room_name: &str) -> Vec<String> {
+        let rooms = self.rooms.read().await;
+        let mut usernames: Vec<String> = Vec::new();
+        for user in rooms.get(room_name).into_iter().flatten() {
+            if !usernames.contains(&user.username) {
+                usernames.push(user.username.clone());
+            }
+        }
+        usernames
+    }
+
+    // get the membership of a socket in a room
+    pub async fn get_user_in_room(&self, socket_id: &str, room_name: &str) -> Option<User> {
         let socket_user = self.socket_users.read().await;
-        socket_user.get(socket_id).cloned()
+        socket_user.get(socket_id)?.get(room_name).cloned()
+    }
+
+    // like `get_user_in_room`, for events that require the socket to be in the room
+    pub async fn require_member(
+        &self,
+        socket_id: &str,
+        room_name: &str,
+    ) -> Result<User, ChatError> {
+        self.get_user_in_room(socket_id, room_name)
+            .await
+            .ok_or_else(|| ChatError::NotInRoom(room_name.to_string()))
+    }
+
+    // get the rooms a socket has joined
+    pub async fn get_socket_rooms(&self, socket_id: &str) -> Vec<String> {
+        let socket_user = self.socket_users.read().await;
+        socket_user
+            .get(socket_id)
+            .map(|socket_rooms| socket_rooms.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // verify a session token and check its session is still active and belongs to the
+    // token's account. The username comes from the account, not from the token
+    pub async fn authenticate_token(&self, token: &str) -> Result<Identity, AuthError> {
+        let claims = self.token_auth.verify(token)?;
+        let session = self
+            .accounts
+            .active_session(&claims.jti)
+            .await
+            .ok_or(AuthError::SessionEnded)?;
+        if session.account_id != claims.sub {
+            return Err(AuthError::SessionEnded);
+        }
+        let account = self
+            .accounts
+            .get(&session.account_id)
+            .await
+            .ok_or(AuthError::SessionEnded)?;
+        Ok(Identity {
+            account_id: account.id,
+            username: account.username,
+        })
+    }
+
+    // bind a verified identity to a socket
+    pub async fn set_socket_identity(&self, socket_id: &str, identity: Identity) {
+        let mut socket_identities = self.socket_identities.write().await;
+        socket_identities.insert(socket_id.to_string(), identity);
+    }
+
+    // get the verified identity of a socket
+    pub async fn get_socket_identity(&self, socket_id: &str) -> Option<Identity> {
+        let socket_identities = self.socket_identities.read().await;
+        socket_identities.get(socket_id).cloned()
+    }
+
+    // forget the identity of a disconnected socket
+    pub async fn remove_socket_identity(&self, socket_id: &str) -> Option<Identity> {
+        let mut socket_identities = self.socket_identities.write().await;
+        socket_identities.remove(socket_id)
+    }
+
+    // count an event against the limits of its socket, account and IP
+    pub async fn check_rate_limit(
+        &self,
+        event: &str,
+        socket_id: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Limited> {
+        let identity = self.get_socket_identity(socket_id).await;
+        let caller = Caller {
+            socket_id,
+            account_id: identity.as_ref().map(|i| i.account_id.as_str()),
+            ip,
+        };
+        let result = self
+            .rate_limiter
+            .write()
+            .await
+            .check(event, caller, Instant::now());
+        if let (Err(limited), Some(identity)) = (&result, &identity) {
+            match limited.penalty {
+                Penalty::Mute { secs } => warn!(
+                    "{} keeps going over the {} limit, muted for {}s",
+                    identity.username, event, secs
+                ),
+                Penalty::Disconnect => warn!(
+                    "{} keeps going over the {} limit, disconnecting socket {}",
+                    identity.username, event, socket_id
+                ),
+                Penalty::None => {}
+            }
+        }
+        result
+    }
+
+    // drop the rate limit state of a disconnected socket
+    pub async fn forget_rate_limits(&self, socket_id: &str) {
+        self.rate_limiter
+            .write()
+            .await
+            .forget_socket(socket_id, Instant::now());
     }
 
     // add message to room
-    pub async fn add_message(&self, message: ChatMessage) {
-        let mut messages = self.messages.write().await;
-        messages
-            .entry(message.room.clone())
-            .or_insert_with(Vec::new)
-            .push(message);
+    pub async fn add_message(&self, message: ChatMessage) -> io::Result<()> {
+        let room = message.room.clone();
+        self.messages.append(message).await?;
+        self.enforce_retention(&room).await?;
+        Ok(())
+    }
+
+    // replace the text of a message, keeping the previous text as a revision
+    pub async fn edit_message(
+        &self,
+        account_id: &str,
+        room_name: &str,
+        message_id: &str,
+        text: String,
+    ) -> Result<ChatMessage, ChatError> {
+        let message = self
+            .messages
+            .get_message(room_name, message_id)
+            .await
+            .filter(|m| !m.deleted)
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))?;
+        if message.account_id.as_deref() != Some(account_id) {
+            return Err(ChatError::Forbidden(
+                "only the author can edit a message".to_string(),
+            ));
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if let Some(window) = self.edit_window_secs {
+            if now.saturating_sub(message.timestamp) > window {
+                return Err(ChatError::EditWindowExpired);
+            }
+        }
+
+        let (tokens, mentions) = self.render_markup(&text).await;
+        let edit = Box::new(move |message: &mut ChatMessage| {
+            if message.message == text {
+                return;
+            }
+            message.tokens = tokens;
+            message.mentions = mentions;
+            let previous = std::mem::replace(&mut message.message, text);
+            message.revisions.push(MessageRevision {
+                message: previous,
+                timestamp: message.edited_at.unwrap_or(message.timestamp),
+            });
+            message.edited_at = Some(now);
+        });
+        self.messages
+            .update(room_name, message_id, edit)
+            .await?
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))
+    }
+
+    // tombstone a message, allowed for its author and the room moderators
+    pub async fn delete_message(
+        &self,
+        user: &User,
+        room_name: &str,
+        message_id: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let message = self
+            .messages
+            .get_message(room_name, message_id)
+            .await
+            .filter(|m| !m.deleted)
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))?;
+        let is_author = message.account_id.as_deref() == Some(user.account_id.as_str());
+        if !is_author {
+            self.require_role(
+                room_name,
+                &user.account_id,
+                &user.username,
+                RoomRole::Moderator,
+                "delete messages of others",
+            )
+            .await?;
+        }
+
+        let deleted = self
+            .messages
+            .update(room_name, message_id, Box::new(ChatMessage::tombstone))
+            .await?
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))?;
+        self.notifications
+            .write()
+            .await
+            .forget_message(room_name, message_id);
+        Ok(deleted)
+    }
+
+    // add or remove a user's emoji reaction on a message
+    pub async fn set_reaction(
+        &self,
+        username: &str,
+        room_name: &str,
+        message_id: &str,
+        emoji: &str,
+        reacted: bool,
+    ) -> Result<ChatMessage, ChatError> {
+        let message = self
+            .messages
+            .get_message(room_name, message_id)
+            .await
+            .filter(|m| !m.deleted)
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))?;
+        let already = message
+            .reactions
+            .iter()
+            .any(|r| r.emoji == emoji && r.users.iter().any(|u| u == username));
+        // nothing to store if the reaction is already in the requested state
+        if already == reacted {
+            return Ok(message);
+        }
+
+        let (emoji, username) = (emoji.to_string(), username.to_string());
+        let edit = Box::new(move |message: &mut ChatMessage| {
+            if reacted {
+                message.add_reaction(&emoji, &username);
+            } else {
+                message.remove_reaction(&emoji, &username);
+            }
+        });
+        self.messages
+            .update(room_name, message_id, edit)
+            .await?
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))
+    }
+
+    // get a message that can still be replied to or reacted on
+    pub async fn get_live_message(
+        &self,
+        room_name: &str,
+        message_id: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        self.messages
+            .get_message(room_name, message_id)
+            .await
+            .filter(|m| !m.deleted)
+            .ok_or_else(|| ChatError::MessageNotFound(message_id.to_string()))
+    }
+
+    // count a new reply on the root message of a thread
+    pub async fn bump_reply_count(
+        &self,
+        room_name: &str,
+        thread_id: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        self.messages
+            .update(
+                room_name,
+                thread_id,
+                Box::new(|root: &mut ChatMessage| root.reply_count += 1),
+            )
+            .await?
+            .ok_or_else(|| ChatError::MessageNotFound(thread_id.to_string()))
+    }
+
+    // get the root message of a thread and all its replies
+    pub async fn get_thread(
+        &self,
+        room_name: &str,
+        thread_id: &str,
+    ) -> Result<ThreadData, ChatError> {
+        let messages = self.messages.room_messages(room_name).await;
+        let root = messages
+            .iter()
+            .find(|m| m.id == thread_id)
+            .cloned()
+            .ok_or_else(|| ChatError::MessageNotFound(thread_id.to_string()))?;
+        let replies = messages
+            .into_iter()
+            .filter(|m| m.thread_id.as_deref() == Some(thread_id))
+            .collect();
+        Ok(ThreadData {
+            room: room_name.to_string(),
+            root,
+            replies,
+        })
     }
 
     // get message from a room
     pub async fn get_room_messages(&self, room_name: &str) -> Vec<ChatMessage> {
-        let messages = self.messages.read().await;
-        messages.get(room_name).cloned().unwrap_or_default()
+        self.messages.room_messages(room_name).await
+    }
+
+    // get one page of room history, older than the `before` message id
+    pub async fn get_history_page(
+        &self,
+        room_name: &str,
+        before: Option<&str>,
+        limit: Option<usize>,
+    ) -> HistoryPage {
+        let limit = limit
+            .unwrap_or(DEFAULT_HISTORY_PAGE_SIZE)
+            .clamp(1, MAX_HISTORY_PAGE_SIZE);
+        self.messages.history_page(room_name, before, limit).await
+    }
+
+    // get the retention policy of a room
+    pub async fn get_room_retention(&self, room_name: &str) -> RetentionPolicy {
+        let room_retention = self.room_retention.read().await;
+        room_retention
+            .get(room_name)
+            .or_else(|| self.configured_retention.get(room_name))
+            .copied()
+            .unwrap_or(self.default_retention)
+    }
+
+    // override the retention policy of a room, within the configured limits
+    pub async fn set_room_retention(&self, room_name: &str, policy: RetentionPolicy) {
+        let policy = policy.capped_by(&self.retention_limit(room_name));
+        let mut room_retention = self.room_retention.write().await;
+        room_retention.insert(room_name.to_string(), policy);
+    }
+
+    // the retention a room can tighten but not loosen: its configured one or the default
+    fn retention_limit(&self, room_name: &str) -> RetentionPolicy {
+        self.configured_retention
+            .get(room_name)
+            .copied()
+            .unwrap_or(self.default_retention)
+    }
+
+    // cap the retention setting of a room, so the room shows the policy actually applied
+    fn cap_retention_setting(&self, room: &mut Room) {
+        if let Some(policy) = &mut room.settings.retention {
+            *policy = policy.capped_by(&self.retention_limit(&room.name));
+        }
+    }
+
+    // go back to the default retention policy for a room
+    pub async fn clear_room_retention(&self, room_name: &str) {
+        let mut room_retention = self.room_retention.write().await;
+        room_retention.remove(room_name);
+    }
+
+    // drop history of a room that falls outside its retention policy
+    pub async fn enforce_retention(&self, room_name: &str) -> io::Result<usize> {
+        let policy = self.get_room_retention(room_name).await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.messages.prune(room_name, &policy, now).await
     }
 
-    // get all rooms with user counts
-    pub async fn get_rooms_info(&self) -> HashMap<String, usize> {
+    // enforce retention on every room with stored history
+    pub async fn sweep_history(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for room in self.messages.room_names().await {
+            removed += self.enforce_retention(&room).await?;
+        }
+        Ok(removed)
+    }
+
+    // forget ad-hoc rooms that nobody joined in time after they were created, returns how many.
+    // Rooms that had users are forgotten as soon as the last one leaves
+    pub async fn sweep_unjoined_rooms(&self) -> usize {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         let rooms = self.rooms.read().await;
-        rooms
+        let mut room_meta = self.room_meta.write().await;
+        let mut invites = self.invites.write().await;
+        let unjoined: Vec<String> = room_meta
+            .values()
+            .filter(|room| {
+                !room.persistent
+                    && !rooms.contains_key(&room.name)
+                    && now.saturating_sub(room.created_at) > UNJOINED_ROOM_TTL_SECS
+            })
+            .map(|room| room.name.clone())
+            .collect();
+        let mut resave = false;
+        for name in &unjoined {
+            resave |= remove_if_ad_hoc(
+                &mut room_meta,
+                &mut invites,
+                self.messages.as_ref(),
+                &self.room_retention,
+                name,
+            )
+            .await;
+        }
+        let removed = unjoined
             .iter()
-            .map(|(name, users)| (name.clone(), users.len()))
-            .collect()
+            .filter(|name| !room_meta.contains_key(*name))
+            .count();
+        drop((rooms, room_meta, invites));
+        if resave {
+            self.save_rooms_or_warn().await;
+        }
+        removed
+    }
+
+    // get the metadata of a room
+    pub async fn get_room(&self, room_name: &str) -> Option<Room> {
+        let room_meta = self.room_meta.read().await;
+        room_meta.get(room_name).cloned()
+    }
+
+    // register a new room, fails if one with the same name exists
+    pub async fn create_room(
+        &self,
+        mut room: Room,
+        password: Option<String>,
+    ) -> Result<Room, ChatError> {
+        check_room_name(&room.name)?;
+        check_room_metadata(&room.topic, &room.description)?;
+        self.cap_retention_setting(&mut room);
+        if room.settings.access == RoomAccess::Password {
+            let password = password.ok_or_else(|| {
+                ChatError::ValidationFailed(
+                    "a password is required for password protected rooms".to_string(),
+                )
+            })?;
+            room.password_hash = Some(hash_room_password(password).await?);
+        }
+        {
+            let mut room_meta = self.room_meta.write().await;
+            if room_meta.contains_key(&room.name) {
+                return Err(ChatError::RoomExists(room.name));
+            }
+            room_meta.insert(room.name.clone(), room.clone());
+        }
+        if let Some(policy) = room.settings.retention {
+            self.set_room_retention(&room.name, policy).await;
+        }
+        if room.is_saved() {
+            self.save_rooms().await?;
+        }
+        Ok(room)
+    }
+
+    // change a room: moderators can set the topic and description, owners anything else
+    pub async fn update_room(
+        &self,
+        identity: &Identity,
+        update: UpdateRoomData,
+    ) -> Result<Room, ChatError> {
+        let current = self
+            .get_room(&update.room)
+            .await
+            .ok_or_else(|| ChatError::RoomNotFound(update.room.clone()))?;
+        let changes_settings = update.settings.is_some() || update.password.is_some();
+        let needed = if changes_settings {
+            RoomRole::Owner
+        } else {
+            RoomRole::Moderator
+        };
+        self.require_role(
+            &update.room,
+            &identity.account_id,
+            &identity.username,
+            needed,
+            "change the room",
+        )
+        .await?;
+        let persistence_changed = update
+            .persistent
+            .is_some_and(|persistent| persistent != current.persistent);
+        if persistence_changed && !self.is_server_moderator(&identity.username) {
+            return Err(ChatError::Forbidden(
+                "only a server moderator can change whether a room is persistent".to_string(),
+            ));
+        }
+        let password_hash = match update.password.clone() {
+            Some(password) => Some(hash_room_password(password).await?),
+            None => None,
+        };
+
+        let room = {
+            let mut room_meta = self.room_meta.write().await;
+            // the room may have emptied out since it was read
+            let room = room_meta
+                .get_mut(&update.room)
+                .ok_or_else(|| ChatError::RoomNotFound(update.room.clone()))?;
+            let topic = update.topic.as_deref().unwrap_or(&room.topic);
+            let description = update.description.as_deref().unwrap_or(&room.description);
+            check_room_metadata(topic, description)?;
+            let access = update
+                .settings
+                .as_ref()
+                .map_or(room.settings.access, |settings| settings.access);
+            if access == RoomAccess::Password
+                && password_hash.is_none()
+                && room.password_hash.is_none()
+            {
+                return Err(ChatError::ValidationFailed(
+                    "a password is required for password protected rooms".to_string(),
+                ));
+            }
+            if let Some(topic) = update.topic {
+                room.topic = topic;
+            }
+            if let Some(description) = update.description {
+                room.description = description;
+            }
+            if let Some(persistent) = update.persistent {
+                room.persistent = persistent;
+            }
+            if let Some(settings) = update.settings.clone() {
+                room.settings = settings;
+                self.cap_retention_setting(room);
+            }
+            if access != RoomAccess::Password {
+                room.password_hash = None;
+            } else if password_hash.is_some() {
+                room.password_hash = password_hash;
+            }
+            room.clone()
+        };
+        if update.settings.is_some() {
+            match room.settings.retention {
+                Some(policy) => self.set_room_retention(&room.name, policy).await,
+                None => self.clear_room_retention(&room.name).await,
+            }
+        }
+        // a room that stops being persistent goes away if nobody is in it
+        if !room.persistent {
+            let rooms = self.rooms.read().await;
+            let mut room_meta = self.room_meta.write().await;
+            let mut invites = self.invites.write().await;
+            if !rooms.contains_key(&room.name) {
+                remove_if_ad_hoc(
+                    &mut room_meta,
+                    &mut invites,
+                    self.messages.as_ref(),
+                    &self.room_retention,
+                    &room.name,
+                )
+                .await;
+            }
+        }
+        if room.is_saved() || current.is_saved() {
+            self.save_rooms().await?;
+        }
+        Ok(room)
+    }
+
+    // whether an account may enter a room without a password or invitation
+    pub async fn has_room_access(&self, identity: &Identity, room: &Room) -> bool {
+        if self.is_server_moderator(&identity.username) {
+            return true;
+        }
+        let rooms = self.rooms.read().await;
+        let invites = self.invites.read().await;
+        is_member_or_invited(identity, room, rooms.get(&room.name), &invites)
+    }
+
+    // give a user a role in a room, only owners can change roles
+    pub async fn set_room_role(
+        &self,
+        identity: &Identity,
+        room_name: &str,
+        to: &str,
+        role: RoomRole,
+    ) -> Result<RoleChangedData, ChatError> {
+        self.require_role(
+            room_name,
+            &identity.account_id,
+            &identity.username,
+            RoomRole::Owner,
+            "change roles",
+        )
+        .await?;
+        let target = self.resolve_account(to).await?;
+
+        let saved = {
+            let mut room_meta = self.room_meta.write().await;
+            let room = room_meta
+                .get_mut(room_name)
+                .ok_or_else(|| ChatError::RoomNotFound(room_name.to_string()))?;
+            let owners = room
+                .roles
+                .values()
+                .filter(|r| **r == RoomRole::Owner)
+                .count();
+            if room.role_of(&target.id) == RoomRole::Owner && role < RoomRole::Owner && owners == 1
+            {
+                return Err(ChatError::ValidationFailed(
+                    "a room needs at least one owner".to_string(),
+                ));
+            }
+            if role == RoomRole::Member {
+                room.roles.remove(&target.id);
+            } else {
+                room.roles.insert(target.id.clone(), role);
+            }
+            room.is_saved()
+        };
+        if saved {
+            self.save_rooms().await?;
+        }
+        Ok(RoleChangedData {
+            room: room_name.to_string(),
+            username: target.username,
+            account_id: target.id,
+            role,
+            by: identity.username.clone(),
+        })
+    }
+
+    // kick, ban, mute or lift a sanction, moderators can only act on users below their role
+    pub async fn moderate(
+        &self,
+        identity: &Identity,
+        data: ModerationData,
+        action: ModerationAction,
+    ) -> Result<ModerationActionData, ChatError> {
+        self.require_role(
+            &data.room,
+            &identity.account_id,
+            &identity.username,
+            RoomRole::Moderator,
+            "moderate users",
+        )
+        .await?;
+        let target = self.resolve_account(&data.to).await?;
+        if target.id == identity.account_id {
+            return Err(ChatError::ValidationFailed(
+                "you can't moderate yourself".to_string(),
+            ));
+        }
+        let actor_role = self
+            .room_role(&data.room, &identity.account_id, &identity.username)
+            .await;
+        let target_role = self
+            .room_role(&data.room, &target.id, &target.username)
+            .await;
+        if target_role >= actor_role {
+            return Err(ChatError::Forbidden(
+                "you can't moderate a user with the same or a higher role".to_string(),
+            ));
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let too_long = || ChatError::ValidationFailed("duration is too long".to_string());
+        let ends_at = data
+            .duration_secs
+            .map(|secs| now.checked_add(secs).ok_or_else(too_long))
+            .transpose()?;
+        let sanction = Sanction {
+            account_id: target.id.clone(),
+            username: target.username.clone(),
+            by: identity.username.clone(),
+            reason: data.reason.clone(),
+            created_at: now,
+            until: ends_at,
+        };
+        let until = match action {
+            ModerationAction::Ban | ModerationAction::Mute => sanction.until,
+            _ => None,
+        };
+        {
+            let mut moderation = self.moderation.write().await;
+            match action {
+                ModerationAction::Kick => {}
+                ModerationAction::Ban => moderation.ban(&data.room, sanction),
+                ModerationAction::Unban => {
+                    moderation.unban(&data.room, &target.id);
+                }
+                ModerationAction::Mute => moderation.mute(&data.room, sanction),
+                ModerationAction::Unmute => {
+                    moderation.unmute(&data.room, &target.id);
+                }
+            }
+        }
+        Ok(ModerationActionData {
+            room: data.room,
+            action,
+            username: target.username,
+            account_id: target.id,
+            by: identity.username.clone(),
+            reason: data.reason,
+            until,
+        })
+    }
+
+    // fail with `banned` if the account is banned from the room
+    pub async fn check_not_banned(
+        &self,
+        room_name: &str,
+        account_id: &str,
+    ) -> Result<(), ChatError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut moderation = self.moderation.write().await;
+        match moderation.active_ban(room_name, account_id, now) {
+            Some(ban) => Err(ChatError::Banned {
+                room: room_name.to_string(),
+                until: ban.until,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // parse a message text: mentions of unknown accounts and references to unknown rooms
+    // stay plain text, mentions get the account id and are listed once each
+    pub async fn render_markup(&self, text: &str) -> (Vec<Token>, Vec<Mention>) {
+        let mut tokens = markup::parse(text);
+        let mut mentions: Vec<Mention> = Vec::new();
+        for token in &mut tokens {
+            let resolved = match token {
+                Token::Mention {
+                    username,
+                    account_id,
+                } => match self.accounts.find_by_username(username).await {
+                    Some(account) => {
+                        if !mentions.iter().any(|m| m.account_id == account.id) {
+                            mentions.push(Mention {
+                                account_id: account.id.clone(),
+                                username: account.username.clone(),
+                            });
+                        }
+                        *username = account.username;
+                        *account_id = Some(account.id);
+                        true
+                    }
+                    None => false,
+                },
+                Token::RoomRef { room } => self.room_meta.read().await.contains_key(room),
+                _ => true,
+            };
+            if !resolved {
+                if let Some(text) = markup::as_text(token) {
+                    *token = Token::Text { text };
+                }
+            }
+        }
+        (markup::merge_text(tokens), mentions)
+    }
+
+    // notify every account @mentioned in a room message, except its author and accounts
+    // that can't read the room; returns the recipients and their notifications
+    pub async fn notify_mentions(
+        &self,
+        message: &ChatMessage,
+    ) -> Vec<(String, MentionNotification)> {
+        let mut notified = Vec::new();
+        for mention in &message.mentions {
+            if message.account_id.as_deref() == Some(mention.account_id.as_str()) {
+                continue;
+            }
+            let identity = Identity {
+                account_id: mention.account_id.clone(),
+                username: mention.username.clone(),
+            };
+            if !self.can_read_room(Some(&identity), &message.room).await {
+                continue;
+            }
+            notified.push((
+                mention.account_id.clone(),
+                MentionNotification::new(message, MENTION_EXCERPT_LEN),
+            ));
+        }
+        let mut notifications = self.notifications.write().await;
+        for (account_id, notification) in &notified {
+            notifications.add_mention(account_id, notification.clone());
+        }
+        notified
+    }
+
+    // mentions of an account it hasn't read yet, oldest first
+    pub async fn unread_mentions(&self, account_id: &str) -> Vec<MentionNotification> {
+        self.notifications.read().await.unread_mentions(account_id)
+    }
+
+    // mark mentions as read, see `MarkMentionsReadData`
+    pub async fn mark_mentions_read(
+        &self,
+        account_id: &str,
+        ids: Option<&[String]>,
+        room: Option<&str>,
+    ) -> usize {
+        self.notifications
+            .write()
+            .await
+            .mark_read(account_id, ids, room)
+    }
+
+    // run a room message through the filter chain, the text to store or why it was refused
+    pub async fn filter_message(&self, user: &User, message: String) -> Result<String, ChatError> {
+        self.run_filters(user, message, false).await
+    }
+
+    // `filter_message` for the new text of an edited message
+    pub async fn filter_edit(&self, user: &User, message: String) -> Result<String, ChatError> {
+        self.run_filters(user, message, true).await
+    }
+
+    async fn run_filters(
+        &self,
+        user: &User,
+        message: String,
+        edit: bool,
+    ) -> Result<String, ChatError> {
+        if self.filters.is_empty() {
+            return Ok(message);
+        }
+        let settings = self.room_settings(&user.room).await;
+        let ctx = FilterContext {
+            room: &user.room,
+            settings: &settings,
+            account_id: &user.account_id,
+            username: &user.username,
+            now: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            edit,
+        };
+        self.filters
+            .run(&ctx, message)
+            .await
+            .map_err(|(filter, reason)| ChatError::MessageRejected {
+                filter: filter.to_string(),
+                reason,
+            })
+    }
+
+    // let the filter chain know a message it allowed was stored
+    pub async fn message_stored(&self, user: &User, message: &str) {
+        if self.filters.is_empty() {
+            return;
+        }
+        let settings = self.room_settings(&user.room).await;
+        let ctx = FilterContext {
+            room: &user.room,
+            settings: &settings,
+            account_id: &user.account_id,
+            username: &user.username,
+            now: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            edit: false,
+        };
+        self.filters.sent(&ctx, message).await;
+    }
+
+    // settings of a room, the defaults for rooms without metadata
+    async fn room_settings(&self, room_name: &str) -> RoomSettings {
+        let room_meta = self.room_meta.read().await;
+        room_meta
+            .get(room_name)
+            .map(|room| room.settings.clone())
+            .unwrap_or_default()
+    }
+
+    // fail with `muted` if the account was muted automatically for going over the rate limits,
+    // in every room and in direct messages
+    pub async fn check_not_auto_muted(
+        &self,
+        room_name: &str,
+        account_id: &str,
+    ) -> Result<(), ChatError> {
+        let Some(secs) = self
+            .rate_limiter
+            .read()
+            .await
+            .muted_for(account_id, Instant::now())
+        else {
+            return Ok(());
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Err(ChatError::Muted {
+            room: room_name.to_string(),
+            until: Some(now + secs),
+        })
+    }
+
+    // fail with `muted` if the account is muted in the room
+    pub async fn check_not_muted(
+        &self,
+        room_name: &str,
+        account_id: &str,
+    ) -> Result<(), ChatError> {
+        self.check_not_auto_muted(room_name, account_id).await?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut moderation = self.moderation.write().await;
+        match moderation.active_mute(room_name, account_id, now) {
+            Some(mute) => Err(ChatError::Muted {
+                room: room_name.to_string(),
+                until: mute.until,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // invite a user to a room, the inviter must be able to enter it
+    pub async fn invite_user(
+        &self,
+        identity: &Identity,
+        room_name: &str,
+        to: &str,
+    ) -> Result<Invitation, ChatError> {
+        let room = self
+            .get_room(room_name)
+            .await
+            .ok_or_else(|| ChatError::RoomNotFound(room_name.to_string()))?;
+        if !self.has_room_access(identity, &room).await {
+            return Err(ChatError::NotInRoom(room.name));
+        }
+        let recipient = self.resolve_account(to).await?;
+        let invitation = Invitation::new(room.name, identity, &recipient);
+        let mut invites = self.invites.write().await;
+        invites.add_invitation(invitation.clone());
+        Ok(invitation)
+    }
+
+    // accept an invitation, its room can then be joined whatever its access mode
+    pub async fn accept_invitation(
+        &self,
+        identity: &Identity,
+        invitation_id: &str,
+    ) -> Result<Invitation, ChatError> {
+        let mut invites = self.invites.write().await;
+        let invitation = invites.take_invitation(invitation_id, &identity.account_id)?;
+        invites.grant(&invitation.room, &identity.account_id);
+        Ok(invitation)
+    }
+
+    pub async fn decline_invitation(
+        &self,
+        identity: &Identity,
+        invitation_id: &str,
+    ) -> Result<Invitation, ChatError> {
+        let mut invites = self.invites.write().await;
+        invites.take_invitation(invitation_id, &identity.account_id)
+    }
+
+    // invitations an account has not answered yet
+    pub async fn pending_invitations(&self, account_id: &str) -> Vec<Invitation> {
+        let invites = self.invites.read().await;
+        invites.pending_for(account_id)
+    }
+
+    // create a shareable invite code, the creator must be able to enter the room
+    pub async fn create_invite_code(
+        &self,
+        identity: &Identity,
+        data: CreateInviteCodeData,
+    ) -> Result<InviteCode, ChatError> {
+        let room = self
+            .get_room(&data.room)
+            .await
+            .ok_or_else(|| ChatError::RoomNotFound(data.room.clone()))?;
+        if !self.has_room_access(identity, &room).await {
+            return Err(ChatError::NotInRoom(room.name));
+        }
+        if data.max_uses == Some(0) {
+            return Err(ChatError::ValidationFailed(
+                "max_uses must be at least 1".to_string(),
+            ));
+        }
+        let expires_in_secs = match data.expires_in_secs {
+            Some(0) => None,
+            Some(secs) => Some(secs),
+            None => Some(DEFAULT_INVITE_CODE_TTL_SECS),
+        };
+        let code = InviteCode::new(
+            room.name,
+            identity.account_id.clone(),
+            expires_in_secs,
+            data.max_uses,
+        )
+        .ok_or_else(|| ChatError::ValidationFailed("expires_in_secs is too long".to_string()))?;
+        let mut invites = self.invites.write().await;
+        invites.add_code(code.clone());
+        Ok(code)
+    }
+
+    // redeem an invite code, its room can then be joined whatever its access mode
+    pub async fn redeem_invite_code(
+        &self,
+        identity: &Identity,
+        code: &str,
+    ) -> Result<InviteCode, ChatError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut invites = self.invites.write().await;
+        let invite = invites.redeem_code(code, now)?;
+        invites.grant(&invite.room, &identity.account_id);
+        Ok(invite)
+    }
+
+    // check the access mode of a room before joining it, unknown rooms are open
+    pub async fn check_join_access(
+        &self,
+        identity: &Identity,
+        room_name: &str,
+        password: Option<&str>,
+    ) -> Result<(), ChatError> {
+        let Some(room) = self.get_room(room_name).await else {
+            return Ok(());
+        };
+        if room.settings.access == RoomAccess::Public || self.has_room_access(identity, &room).await
+        {
+            return Ok(());
+        }
+        if room.settings.access == RoomAccess::InviteOnly {
+            return Err(ChatError::InviteRequired(room.name));
+        }
+        let verified = match (password, room.password_hash) {
+            (Some(password), Some(hash)) => verify_password(password.to_string(), hash).await,
+            _ => false,
+        };
+        if verified {
+            Ok(())
+        } else {
+            Err(ChatError::WrongRoomPassword(room.name))
+        }
+    }
+
+    // whether the history of a room may be read, only public rooms are open to non-members
+    pub async fn can_read_room(&self, viewer: Option<&Identity>, room_name: &str) -> bool {
+        let Some(room) = self.get_room(room_name).await else {
+            return true;
+        };
+        if room.settings.access == RoomAccess::Public {
+            return true;
+        }
+        match viewer {
+            Some(viewer) => self.has_room_access(viewer, &room).await,
+            None => false,
+        }
+    }
+
+    // load the saved persistent rooms and create the `seeds` that don't exist yet.
+    // Saved ad-hoc rooms emptied with the restart, they are forgotten with their history
+    pub async fn load_rooms(&self, seeds: Vec<String>) -> io::Result<usize> {
+        let mut loaded = self.room_store.load().await?;
+        let mut seeded = false;
+        let mut forgotten = false;
+        for room in loaded.iter().filter(|room| !room.persistent) {
+            self.messages.clear_room(&room.name).await?;
+            forgotten = true;
+        }
+        loaded.retain(|room| room.persistent);
+        for name in seeds {
+            if check_room_name(&name).is_err() {
+                warn!("ignoring invalid persistent room name {:?}", name);
+                continue;
+            }
+            if !loaded.iter().any(|room| room.name == name) {
+                let mut room = Room::new(name);
+                room.persistent = true;
+                loaded.push(room);
+                seeded = true;
+            }
+        }
+
+        let count = loaded.len();
+        {
+            let mut room_meta = self.room_meta.write().await;
+            for mut room in loaded {
+                self.cap_retention_setting(&mut room);
+                if let Some(policy) = room.settings.retention {
+                    self.set_room_retention(&room.name, policy).await;
+                }
+                room_meta.insert(room.name.clone(), room);
+            }
+        }
+        if seeded || forgotten {
+            self.save_rooms().await?;
+        }
+        Ok(count)
+    }
+
+    // write every persistent or private room to the room store
+    pub async fn save_rooms(&self) -> io::Result<()> {
+        // hold the read lock so no newer change is saved before this snapshot
+        let room_meta = self.room_meta.read().await;
+        let mut saved: Vec<Room> = room_meta
+            .values()
+            .filter(|room| room.is_saved())
+            .cloned()
+            .collect();
+        saved.sort_by(|a, b| a.name.cmp(&b.name));
+        self.room_store.save(&saved).await
+    }
+
+    // `save_rooms` for changes nobody can be told about, like a room emptying on disconnect
+    async fn save_rooms_or_warn(&self) {
+        if let Err(e) = self.save_rooms().await {
+            warn!("failed to save rooms: {}", e);
+        }
+    }
+
+    // get all rooms with their metadata and user counts, sorted by name
+    // non-public rooms are only listed for their members, invited users and moderators
+    pub async fn get_rooms_info(&self, viewer: Option<&Identity>) -> Vec<RoomInfo> {
+        let is_moderator = viewer.is_some_and(|viewer| self.is_server_moderator(&viewer.username));
+        let rooms = self.rooms.read().await;
+        let room_meta = self.room_meta.read().await;
+        let invites = self.invites.read().await;
+        let mut infos: Vec<RoomInfo> = room_meta
+            .values()
+            .filter(|room| {
+                room.settings.access == RoomAccess::Public
+                    || is_moderator
+                    || viewer.is_some_and(|viewer| {
+                        is_member_or_invited(viewer, room, rooms.get(&room.name), &invites)
+                    })
+            })
+            .map(|room| RoomInfo {
+                room: room.clone(),
+                user_count: rooms.get(&room.name).map_or(0, Vec::len),
+            })
+            .collect();
+        infos.sort_by(|a, b| a.room.name.cmp(&b.room.name));
+        infos
+    }
+}
+
+// whether an account has a role in a room, has a socket in it or was invited to it
+fn is_member_or_invited(
+    identity: &Identity,
+    room: &Room,
+    members: Option<&Vec<User>>,
+    invites: &InviteBook,
+) -> bool {
+    room.role_of(&identity.account_id) > RoomRole::Member
+        || invites.is_granted(&room.name, &identity.account_id)
+        || members.is_some_and(|members| {
+            members
+                .iter()
+                .any(|user| user.account_id == identity.account_id)
+        })
+}
+
+// hash a room password, refusing empty ones
+async fn hash_room_password(password: String) -> Result<String, ChatError> {
+    if password.is_empty() {
+        return Err(ChatError::ValidationFailed(
+            "room password must not be empty".to_string(),
+        ));
+    }
+    Ok(hash_password(password).await?)
+}
+
+// forget the metadata, invitations and retention override of a room that just emptied,
+// unless it is persistent. Anyone can recreate a forgotten room as a public one, so a private
+// room loses its history too, and is kept if that fails. Returns true if a saved room was
+// forgotten
+async fn remove_if_ad_hoc(
+    room_meta: &mut HashMap<String, Room>,
+    invites: &mut InviteBook,
+    messages: &dyn MessageStore,
+    room_retention: &TokioRwLock<HashMap<String, RetentionPolicy>>,
+    room_name: &str,
+) -> bool {
+    let Some(room) = room_meta.get(room_name).filter(|room| !room.persistent) else {
+        return false;
+    };
+    let saved = room.is_saved();
+    if saved {
+        if let Err(e) = messages.clear_room(room_name).await {
+            warn!(
+                "keeping room {}, its history could not be deleted: {}",
+                room_name, e
+            );
+            return false;
+        }
     }
+    room_meta.remove(room_name);
+    invites.forget_room(room_name);
+    room_retention.write().await.remove(room_name);
+    saved
 }
 
 impl Default for AppState {
@@ -0,0 +1,403 @@
+use crate::models::{ChatMessage, HistoryPage};
+use crate::retention::RetentionPolicy;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, path::PathBuf};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::{Mutex as TokioMutex, RwLock as TokioRwLock},
+};
+use tracing::{info, warn};
+
+// In-place change applied to a stored message
+pub type MessageEdit = Box<dyn FnOnce(&mut ChatMessage) + Send>;
+
+// Storage backend for room message history
+#[async_trait]
+pub trait MessageStore: Send + Sync + std::fmt::Debug {
+    // append a message to its room history
+    async fn append(&self, message: ChatMessage) -> io::Result<()>;
+
+    // get all messages of a room, oldest first
+    async fn room_messages(&self, room_name: &str) -> Vec<ChatMessage>;
+
+    // get up to `limit` messages older than the `before` message id (or the latest ones)
+    async fn history_page(
+        &self,
+        room_name: &str,
+        before: Option<&str>,
+        limit: usize,
+    ) -> HistoryPage {
+        let messages = self.room_messages(room_name).await;
+        page_of(room_name, &messages, before, limit)
+    }
+
+    // get one message of a room by id
+    async fn get_message(&self, room_name: &str, id: &str) -> Option<ChatMessage> {
+        self.room_messages(room_name)
+            .await
+            .into_iter()
+            .find(|m| m.id == id)
+    }
+
+    // apply an edit to a stored message, returns the updated message (None if not found)
+    async fn update(
+        &self,
+        room_name: &str,
+        id: &str,
+        edit: MessageEdit,
+    ) -> io::Result<Option<ChatMessage>>;
+
+    // names of all rooms that have stored history
+    async fn room_names(&self) -> Vec<String>;
+
+    // drop the oldest messages of a room that fall outside the policy, returns how many
+    async fn prune(&self, room_name: &str, policy: &RetentionPolicy, now: u64)
+        -> io::Result<usize>;
+
+    // drop the whole history of a room, returns how many messages were removed
+    async fn clear_room(&self, room_name: &str) -> io::Result<usize> {
+        let nothing_kept = RetentionPolicy {
+            max_messages: Some(0),
+            ..RetentionPolicy::default()
+        };
+        self.prune(room_name, &nothing_kept, 0).await
+    }
+}
+
+// slice one page out of a room history ordered oldest first
+fn page_of(
+    room_name: &str,
+    messages: &[ChatMessage],
+    before: Option<&str>,
+    limit: usize,
+) -> HistoryPage {
+    let end = match before {
+        Some(id) => messages.iter().position(|m| m.id == id).unwrap_or(0),
+        None => messages.len(),
+    };
+    let start = end.saturating_sub(limit);
+    HistoryPage {
+        room: room_name.to_string(),
+        messages: messages[start..end].to_vec(),
+        has_more: start > 0,
+    }
+}
+
+// Message history kept in memory only, lost on restart
+#[derive(Debug, Default)]
+pub struct InMemoryMessageStore {
+    messages: TokioRwLock<HashMap<String, Vec<ChatMessage>>>,
+}
+
+impl InMemoryMessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // drop messages outside the policy and return their ids
+    async fn prune_ids(&self, room_name: &str, policy: &RetentionPolicy, now: u64) -> Vec<String> {
+        let mut messages = self.messages.write().await;
+        let Some(room_messages) = messages.get_mut(room_name) else {
+            return Vec::new();
+        };
+        let excess = policy.excess(room_messages, now);
+        let removed = room_messages.drain(..excess).map(|m| m.id).collect();
+        if room_messages.is_empty() {
+            messages.remove(room_name);
+        }
+        removed
+    }
+
+    // insert a message or replace the one with the same id, returns true if it replaced one
+    async fn upsert(&self, message: ChatMessage) -> bool {
+        let mut messages = self.messages.write().await;
+        let room_messages = messages.entry(message.room.clone()).or_default();
+        match room_messages.iter_mut().find(|m| m.id == message.id) {
+            Some(existing) => {
+                *existing = message;
+                true
+            }
+            None => {
+                room_messages.push(message);
+                false
+            }
+        }
+    }
+
+    // remove messages by id, used when replaying the log
+    async fn remove_ids(&self, room_name: &str, ids: &[String]) {
+        let mut messages = self.messages.write().await;
+        if let Some(room_messages) = messages.get_mut(room_name) {
+            room_messages.retain(|m| !ids.contains(&m.id));
+            if room_messages.is_empty() {
+                messages.remove(room_name);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for InMemoryMessageStore {
+    async fn append(&self, message: ChatMessage) -> io::Result<()> {
+        let mut messages = self.messages.write().await;
+        messages
+            .entry(message.room.clone())
+            .or_insert_with(Vec::new)
+            .push(message);
+        Ok(())
+    }
+
+    async fn room_messages(&self, room_name: &str) -> Vec<ChatMessage> {
+        let messages = self.messages.read().await;
+        messages.get(room_name).cloned().unwrap_or_default()
+    }
+
+    async fn history_page(
+        &self,
+        room_name: &str,
+        before: Option<&str>,
+        limit: usize,
+    ) -> HistoryPage {
+        let messages = self.messages.read().await;
+        let room_messages = messages
+            .get(room_name)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        page_of(room_name, room_messages, before, limit)
+    }
+
+    async fn get_message(&self, room_name: &str, id: &str) -> Option<ChatMessage> {
+        let messages = self.messages.read().await;
+        messages
+            .get(room_name)?
+            .iter()
+            .find(|m| m.id == id)
+            .cloned()
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        id: &str,
+        edit: MessageEdit,
+    ) -> io::Result<Option<ChatMessage>> {
+        let mut messages = self.messages.write().await;
+        let message = messages
+            .get_mut(room_name)
+            .and_then(|room_messages| room_messages.iter_mut().find(|m| m.id == id));
+        Ok(message.map(|message| {
+            edit(message);
+            message.clone()
+        }))
+    }
+
+    async fn room_names(&self) -> Vec<String> {
+        let messages = self.messages.read().await;
+        messages.keys().cloned().collect()
+    }
+
+    async fn prune(
+        &self,
+        room_name: &str,
+        policy: &RetentionPolicy,
+        now: u64,
+    ) -> io::Result<usize> {
+        Ok(self.prune_ids(room_name, policy, now).await.len())
+    }
+}
+
+// One line of the append-only message log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogRecord {
+    Put { message: Box<ChatMessage> },
+    Remove { room: String, ids: Vec<String> },
+}
+
+// Message history stored in an append-only JSON lines file.
+// The whole log is replayed into memory on open, so reads never touch the disk.
+#[derive(Debug)]
+pub struct FileMessageStore {
+    path: PathBuf,
+    index: InMemoryMessageStore,
+    file: TokioMutex<File>,
+}
+
+impl FileMessageStore {
+    pub async fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .await?;
+
+        // replay the log into the in-memory index
+        let index = InMemoryMessageStore::new();
+        let mut lines = BufReader::new(file.try_clone().await?).lines();
+        // records made obsolete by a later remove or edit
+        let mut stale = 0;
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<LogRecord>(&line) {
+                // a later `put` of the same id is an edit
+                Ok(LogRecord::Put { message }) => {
+                    if index.upsert(*message).await {
+                        stale += 1;
+                    }
+                }
+                Ok(LogRecord::Remove { room, ids }) => {
+                    index.remove_ids(&room, &ids).await;
+                    stale += ids.len();
+                }
+                Err(e) => warn!("skipping malformed line in {}: {}", path.display(), e),
+            }
+        }
+
+        let store = Self {
+            path,
+            index,
+            file: TokioMutex::new(file),
+        };
+
+        // rewrite the log without stale records so it doesn't grow forever
+        if stale > 0 {
+            store.compact().await?;
+        }
+
+        let mut count = 0;
+        for room in store.index.room_names().await {
+            count += store.index.room_messages(&room).await.len();
+        }
+        info!("Loaded {} messages from {}", count, store.path.display());
+
+        Ok(store)
+    }
+
+    // replace the log with one `put` record per live message
+    async fn compact(&self) -> io::Result<()> {
+        let mut file = self.file.lock().await;
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = File::create(&tmp_path).await?;
+        for room in self.index.room_names().await {
+            for message in self.index.room_messages(&room).await {
+                self.write_record(
+                    &mut tmp,
+                    &LogRecord::Put {
+                        message: Box::new(message),
+                    },
+                )
+                .await?;
+            }
+        }
+        tmp.sync_all().await?;
+        drop(tmp);
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+
+        *file = OpenOptions::new().append(true).open(&self.path).await?;
+        Ok(())
+    }
+
+    // write one record to the end of the log
+    async fn write_record(&self, file: &mut File, record: &LogRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+}
+
+#[async_trait]
+impl MessageStore for FileMessageStore {
+    async fn append(&self, message: ChatMessage) -> io::Result<()> {
+        // hold the file lock until the index is updated so both stay in the same order
+        let mut file = self.file.lock().await;
+        let record = LogRecord::Put {
+            message: Box::new(message.clone()),
+        };
+        if let Err(e) = self.write_record(&mut file, &record).await {
+            warn!("failed to append to {}: {}", self.path.display(), e);
+            return Err(e);
+        }
+        self.index.append(message).await
+    }
+
+    async fn room_messages(&self, room_name: &str) -> Vec<ChatMessage> {
+        self.index.room_messages(room_name).await
+    }
+
+    async fn history_page(
+        &self,
+        room_name: &str,
+        before: Option<&str>,
+        limit: usize,
+    ) -> HistoryPage {
+        self.index.history_page(room_name, before, limit).await
+    }
+
+    async fn get_message(&self, room_name: &str, id: &str) -> Option<ChatMessage> {
+        self.index.get_message(room_name, id).await
+    }
+
+    async fn update(
+        &self,
+        room_name: &str,
+        id: &str,
+        edit: MessageEdit,
+    ) -> io::Result<Option<ChatMessage>> {
+        let mut file = self.file.lock().await;
+        let Some(message) = self.index.get_message(room_name, id).await else {
+            return Ok(None);
+        };
+        // write the edited message first, the index only changes once it is on disk
+        let mut edited = message;
+        edit(&mut edited);
+        let record = LogRecord::Put {
+            message: Box::new(edited.clone()),
+        };
+        if let Err(e) = self.write_record(&mut file, &record).await {
+            warn!("failed to append to {}: {}", self.path.display(), e);
+            return Err(e);
+        }
+        self.index.upsert(edited.clone()).await;
+        Ok(Some(edited))
+    }
+
+    async fn room_names(&self) -> Vec<String> {
+        self.index.room_names().await
+    }
+
+    async fn prune(
+        &self,
+        room_name: &str,
+        policy: &RetentionPolicy,
+        now: u64,
+    ) -> io::Result<usize> {
+        let mut file = self.file.lock().await;
+        let ids = self.index.prune_ids(room_name, policy, now).await;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let count = ids.len();
+        let record = LogRecord::Remove {
+            room: room_name.to_string(),
+            ids,
+        };
+        if let Err(e) = self.write_record(&mut file, &record).await {
+            warn!("failed to append to {}: {}", self.path.display(), e);
+            return Err(e);
+        }
+        Ok(count)
+    }
+}
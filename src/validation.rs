@@ -0,0 +1,355 @@
+use crate::errors::ChatError;
+use crate::invites::MAX_INVITE_CODE_TTL_SECS;
+use crate::models::*;
+use crate::state::{MAX_DESCRIPTION_LEN, MAX_EMOJI_LEN, MAX_TOPIC_LEN};
+use unicode_normalization::UnicodeNormalization;
+
+// Longest accepted room name, in characters
+pub const MAX_ROOM_NAME_LEN: usize = 64;
+// Longest accepted chat or direct message, in characters
+pub const MAX_MESSAGE_LEN: usize = 4000;
+// Longest username or account id accepted to address a user
+pub const MAX_USER_REF_LEN: usize = 64;
+// Longest message, invitation or thread id
+pub const MAX_ID_LEN: usize = 64;
+// Longest room password, in bytes (argon2 hashes the whole thing)
+pub const MAX_PASSWORD_LEN: usize = 256;
+// Longest moderation reason, in characters
+pub const MAX_REASON_LEN: usize = 200;
+// Longest timed ban or mute, longer ones are given without a duration
+pub const MAX_SANCTION_SECS: u64 = 365 * 24 * 60 * 60;
+
+// Characters room names can't contain: mention and path separators
+const FORBIDDEN_ROOM_NAME_CHARS: &[char] = &['/', '\\', '#', '@', ':'];
+
+// An inbound event payload. `validate` normalizes the payload in place (Unicode NFC,
+// surrounding whitespace trimmed) and refuses it with `validation_failed` if it breaks a rule.
+// Every Socket.IO event payload is validated before its handler runs.
+pub trait Validate {
+    fn validate(&mut self) -> Result<(), ChatError>;
+}
+
+// refuse room names that are empty, too long, contain forbidden characters
+// or clash with the internal `user:` and `dm:` rooms
+pub fn check_room_name(room_name: &str) -> Result<(), ChatError> {
+    if room_name.trim().is_empty() {
+        return Err(ChatError::ValidationFailed(
+            "room name must not be empty".to_string(),
+        ));
+    }
+    if room_name.starts_with("user:") || room_name.starts_with("dm:") {
+        return Err(ChatError::ValidationFailed(format!(
+            "room name {} is reserved",
+            room_name
+        )));
+    }
+    if room_name.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(ChatError::ValidationFailed(format!(
+            "room name must be at most {} characters",
+            MAX_ROOM_NAME_LEN
+        )));
+    }
+    if room_name
+        .chars()
+        .any(|c| FORBIDDEN_ROOM_NAME_CHARS.contains(&c) || c.is_control() || is_invisible(c))
+    {
+        return Err(ChatError::ValidationFailed(format!(
+            "room name must not contain control characters or any of {}",
+            FORBIDDEN_ROOM_NAME_CHARS.iter().collect::<String>()
+        )));
+    }
+    Ok(())
+}
+
+// normalize a room name and check it
+pub fn normalize_room_name(name: &mut String) -> Result<(), ChatError> {
+    normalize(name);
+    check_room_name(name)
+}
+
+// NFC and no surrounding whitespace, so the same text always compares equal
+fn normalize(s: &mut String) {
+    let normalized: String = s.trim().nfc().collect();
+    *s = normalized;
+}
+
+// zero-width, bidi control and other invisible characters, only allowed inside messages
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{061C}'
+            | '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}'
+    )
+}
+
+// free text: normalized, at most `max` characters, no control characters
+// (line breaks and tabs are allowed when `multiline`)
+fn text(field: &mut String, what: &str, max: usize, multiline: bool) -> Result<(), ChatError> {
+    normalize(field);
+    if field.chars().count() > max {
+        return Err(ChatError::ValidationFailed(format!(
+            "{} must be at most {} characters",
+            what, max
+        )));
+    }
+    let allowed = |c: char| multiline && (c == '\n' || c == '\t');
+    if field.chars().any(|c| c.is_control() && !allowed(c)) {
+        return Err(ChatError::ValidationFailed(format!(
+            "{} must not contain control characters",
+            what
+        )));
+    }
+    Ok(())
+}
+
+// a chat message: like `text` but never empty, nor only made of invisible characters
+fn message(field: &mut String) -> Result<(), ChatError> {
+    text(field, "message", MAX_MESSAGE_LEN, true)?;
+    if field.chars().all(|c| c.is_whitespace() || is_invisible(c)) {
+        return Err(ChatError::ValidationFailed(
+            "message must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// a username, account id or message id: a single non-empty word
+fn word(field: &mut String, what: &str, max: usize) -> Result<(), ChatError> {
+    normalize(field);
+    if field.is_empty()
+        || field.chars().count() > max
+        || field
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || is_invisible(c))
+    {
+        return Err(ChatError::ValidationFailed(format!(
+            "{} must be 1-{} characters without spaces",
+            what, max
+        )));
+    }
+    Ok(())
+}
+
+fn user_ref(field: &mut String) -> Result<(), ChatError> {
+    word(field, "user", MAX_USER_REF_LEN)
+}
+
+fn id(field: &mut String, what: &str) -> Result<(), ChatError> {
+    word(field, what, MAX_ID_LEN)
+}
+
+// passwords are compared as typed, only their length is checked
+fn password(field: &Option<String>) -> Result<(), ChatError> {
+    if field.as_ref().is_some_and(|p| p.len() > MAX_PASSWORD_LEN) {
+        return Err(ChatError::ValidationFailed(format!(
+            "password must be at most {} bytes",
+            MAX_PASSWORD_LEN
+        )));
+    }
+    Ok(())
+}
+
+impl Validate for JoinRoomData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        password(&self.password)
+    }
+}
+
+impl Validate for LeaveRoomData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)
+    }
+}
+
+impl Validate for TypingRoomData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)
+    }
+}
+
+impl Validate for CreateRoomData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.name)?;
+        text(&mut self.topic, "topic", MAX_TOPIC_LEN, false)?;
+        text(
+            &mut self.description,
+            "description",
+            MAX_DESCRIPTION_LEN,
+            true,
+        )?;
+        password(&self.password)
+    }
+}
+
+impl Validate for UpdateRoomData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        if let Some(topic) = &mut self.topic {
+            text(topic, "topic", MAX_TOPIC_LEN, false)?;
+        }
+        if let Some(description) = &mut self.description {
+            text(description, "description", MAX_DESCRIPTION_LEN, true)?;
+        }
+        password(&self.password)
+    }
+}
+
+impl Validate for GrantRoleData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        user_ref(&mut self.to)
+    }
+}
+
+impl Validate for RevokeRoleData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        user_ref(&mut self.to)
+    }
+}
+
+impl Validate for ModerationData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        user_ref(&mut self.to)?;
+        if let Some(reason) = &mut self.reason {
+            text(reason, "reason", MAX_REASON_LEN, false)?;
+        }
+        if self
+            .duration_secs
+            .is_some_and(|secs| secs == 0 || secs > MAX_SANCTION_SECS)
+        {
+            return Err(ChatError::ValidationFailed(format!(
+                "duration must be 1-{} seconds, omit it for no end",
+                MAX_SANCTION_SECS
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for InviteUserData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        user_ref(&mut self.to)
+    }
+}
+
+impl Validate for InvitationResponseData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        id(&mut self.invitation_id, "invitation id")
+    }
+}
+
+impl Validate for CreateInviteCodeData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        if self
+            .expires_in_secs
+            .is_some_and(|secs| secs > MAX_INVITE_CODE_TTL_SECS)
+        {
+            return Err(ChatError::ValidationFailed(format!(
+                "expires_in_secs must be at most {} seconds",
+                MAX_INVITE_CODE_TTL_SECS
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for SendMessageData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        message(&mut self.message)?;
+        if let Some(reply_to) = &mut self.reply_to {
+            id(reply_to, "reply_to")?;
+        }
+        Ok(())
+    }
+}
+
+impl Validate for EditMessageData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        id(&mut self.message_id, "message id")?;
+        message(&mut self.message)
+    }
+}
+
+impl Validate for DeleteMessageData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        id(&mut self.message_id, "message id")
+    }
+}
+
+impl Validate for ReactionData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        id(&mut self.message_id, "message id")?;
+        normalize(&mut self.emoji);
+        if self.emoji.is_empty()
+            || self.emoji.len() > MAX_EMOJI_LEN
+            || self
+                .emoji
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(ChatError::ValidationFailed(
+                "reaction must be a single emoji".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for LoadHistoryData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        if let Some(before) = &mut self.before {
+            id(before, "before")?;
+        }
+        Ok(())
+    }
+}
+
+impl Validate for LoadThreadData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        normalize_room_name(&mut self.room)?;
+        id(&mut self.thread_id, "thread id")
+    }
+}
+
+impl Validate for SendDirectMessageData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        user_ref(&mut self.to)?;
+        message(&mut self.message)
+    }
+}
+
+impl Validate for MarkMentionsReadData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        if let Some(room) = &mut self.room {
+            normalize_room_name(room)?;
+        }
+        for mention_id in self.ids.iter_mut().flatten() {
+            id(mention_id, "mention id")?;
+        }
+        Ok(())
+    }
+}
+
+impl Validate for LoadDirectMessagesData {
+    fn validate(&mut self) -> Result<(), ChatError> {
+        user_ref(&mut self.with)?;
+        if let Some(before) = &mut self.before {
+            id(before, "before")?;
+        }
+        Ok(())
+    }
+}